@@ -1,17 +1,26 @@
-//! Code to parse the command line using `clap`, and definitions of the parsed result
+//! Code to parse the command line using `clap`, and definitions of the parsed result.
+//! Only built with the `cli` feature, since it's only needed by the `zet` command.
 
 use crate::help;
-use crate::operations::LogType;
-use crate::styles::{set_color_choice, ColorChoice};
-use clap::{Parser, ValueEnum};
+use crate::histogram::{Buckets, Histogram, HistogramBy};
+use crate::operations::{CountMode, Operation, Reading};
+use crate::order::{Sequence, SortOrder};
+use crate::output::{CountPosition, Output, OutputFormat, Saturated, Selection};
+use crate::styles::{set_color_choice, ColorChoice, StyleSheet};
+use crate::template::Template;
+use clap::parser::ValueSource;
+use clap::{Arg, ArgMatches, CommandFactory, FromArgMatches, Parser, ValueEnum};
+use std::num::NonZeroUsize;
 use std::path::PathBuf;
+use std::thread;
 
 /// Returns the parsed command line: the `Args` return value's `op` field is the set operation
 /// desired, and the `files` field holds the files to take as operands.
 #[must_use]
 pub fn parsed() -> Args {
-    let parsed = CliArgs::parse();
-    let cc = parsed.color.unwrap_or(ColorChoice::Auto);
+    let matches = CliArgs::command().get_matches();
+    let mut parsed = CliArgs::from_arg_matches(&matches).unwrap_or_else(|error| error.exit());
+    let cc = parsed.color.take().unwrap_or(ColorChoice::Auto);
     set_color_choice(cc);
     if parsed.help {
         help_and_exit();
@@ -24,41 +33,192 @@ pub fn parsed() -> Args {
     if op == CliName::Help {
         help_and_exit()
     }
-    let log_type = if parsed.count_files {
-        LogType::Files
-    } else if parsed.count_lines {
-        LogType::Lines
-    } else if parsed.count {
-        if parsed.files {
-            LogType::Files
-        } else {
-            LogType::Lines
-        }
-    } else {
-        LogType::None
-    };
+    let build_index = op == CliName::Index;
+    if build_index {
+        check_index_build(&mut parsed.paths, parsed.output.is_some(), &matches);
+    }
+    let log_type = log_type(&parsed);
 
-    let op = match op {
+    let op_name = |op| match op {
         CliName::Help => help_and_exit(), // This can't happen, but...
-        CliName::Intersect => OpName::Intersect,
-        CliName::Union => OpName::Union,
-        CliName::Diff => OpName::Diff,
+        CliName::Intersect => Operation::Intersect,
+        CliName::Union | CliName::Index => Operation::Union, // For `Index`, `op` isn't used
+        CliName::Diff => Operation::Diff,
         CliName::Single => {
             if parsed.files {
-                OpName::SingleByFile
+                Operation::SingleByFile
             } else {
-                OpName::Single
+                Operation::Single
             }
         }
         CliName::Multiple => {
             if parsed.files {
-                OpName::MultipleByFile
+                Operation::MultipleByFile
             } else {
-                OpName::Multiple
+                Operation::Multiple
             }
         }
     };
-    Args { op, log_type, paths: parsed.paths }
+    let op = op_name(op);
+    let emits = parsed.emit.iter().map(|(op, path)| (op_name(*op), path.clone())).collect();
+    let output = Output {
+        format: parsed.output_format.unwrap_or_default(),
+        provenance: parsed.provenance,
+        operand_names: operand_names(&parsed.paths),
+        count_position: parsed.count_position.unwrap_or_default(),
+        count_separator: parsed.count_separator.unwrap_or_else(|| " ".to_string()),
+        saturated: parsed.saturated.unwrap_or_default(),
+        sort: parsed.sort.map_or_else(SortOrder::default, |(sort, _)| sort),
+        // `--sort last-seen` is short for `--order last-seen`, which overrides it
+        order: parsed.order.or(parsed.sort.map(|(_, order)| order)).unwrap_or_default(),
+        reverse: parsed.reverse,
+        select: match (parsed.top, parsed.bottom) {
+            (Some(n), _) => Some(Selection::Top(n)),
+            (None, Some(n)) => Some(Selection::Bottom(n)),
+            (None, None) => None,
+        },
+        // Rank by the count we're reporting, or if we're not reporting a
+        // count, by the count that `--count` would report.
+        select_by_files: match log_type {
+            CountMode::Files => true,
+            CountMode::Lines => false,
+            CountMode::None => parsed.files,
+        },
+        // Set by `main`, which knows where the result is going
+        style: StyleSheet::default(),
+        template: parsed.format,
+        // `main` decides whether to draw bars
+        histogram: parsed.histogram.map(|by| Histogram {
+            by,
+            buckets: parsed.buckets.unwrap_or_default(),
+            bars: false,
+        }),
+    };
+    let threads = match parsed.threads {
+        Some(0) => thread::available_parallelism().map_or(1, NonZeroUsize::get),
+        Some(n) => n,
+        None => 1,
+    };
+    let reading = Reading {
+        threads,
+        hash_only: parsed.hash_only,
+        verify_hashes: parsed.verify_hashes,
+        memory_limit: parsed.memory_limit,
+        sorted: parsed.sorted,
+        stream: parsed.stream,
+        // Set by `main`, which plans the order to read the operands in
+        base: None,
+        max_memory: parsed.max_memory,
+    };
+    let (base, verbose, watch, output_file, paths) =
+        (parsed.base, parsed.verbose, parsed.watch, parsed.output, parsed.paths);
+    Args {
+        op,
+        build_index,
+        log_type,
+        output,
+        reading,
+        base,
+        verbose,
+        watch,
+        output_file,
+        emits,
+        paths,
+    }
+}
+
+/// The count requested by `--count`, `--count-files`, `--count-lines`, or
+/// `--count-none`
+fn log_type(parsed: &CliArgs) -> CountMode {
+    if parsed.count_files {
+        CountMode::Files
+    } else if parsed.count_lines {
+        CountMode::Lines
+    } else if parsed.count {
+        if parsed.files {
+            CountMode::Files
+        } else {
+            CountMode::Lines
+        }
+    } else {
+        CountMode::None
+    }
+}
+
+/// Parse an `--emit` value of the form `OP=PATH`, where `OP` is any command
+/// but `help`.
+fn parse_emit(value: &str) -> Result<(CliName, PathBuf), String> {
+    let Some((op, path)) = value.split_once('=') else {
+        return Err(format!("expected OP=PATH, not '{value}'"));
+    };
+    let op = CliName::from_str(op, true)?;
+    if matches!(op, CliName::Help | CliName::Index) {
+        return Err(format!(
+            "'{}' isn't a set operation",
+            op.to_possible_value().unwrap().get_name()
+        ));
+    }
+    if path.is_empty() {
+        return Err(format!("no path given for '{value}'"));
+    }
+    Ok((op, PathBuf::from(path)))
+}
+
+/// Parse a `--sort` value: a `SortOrder`, or `last-seen`, which is short for
+/// `--order last-seen`. Returns the sort order and the sequence it implies.
+fn parse_sort(value: &str) -> Result<(SortOrder, Sequence), String> {
+    if value.eq_ignore_ascii_case("last-seen") {
+        return Ok((SortOrder::FirstSeen, Sequence::LastSeen));
+    }
+    Ok((SortOrder::from_str(value, true)?, Sequence::FirstSeen))
+}
+
+/// Parse a `--base` value: an operand's position on the command line, counting
+/// from `1`.
+fn parse_position(value: &str) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(0) => Err("operands are numbered from 1".to_string()),
+        Ok(position) => Ok(position),
+        Err(_) => Err(format!("'{value}' isn't a number")),
+    }
+}
+
+/// Parse a `--memory-limit` or `--max-memory` value: a number of bytes, optionally followed by
+/// `K`, `M`, `G`, or `T` (for powers of 1024), which may in turn be followed
+/// by `B` or `iB`.
+fn parse_size(value: &str) -> Result<u64, String> {
+    let digits = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (number, suffix) = value.split_at(digits);
+    let Ok(number) = number.parse::<u64>() else {
+        return Err(format!("expected a size like 512M or 2G, not '{value}'"));
+    };
+    let suffix = suffix.to_ascii_uppercase();
+    let unit = suffix.strip_suffix("IB").or_else(|| suffix.strip_suffix('B')).unwrap_or(&suffix);
+    let power = match unit {
+        "" => 0,
+        "K" => 1,
+        "M" => 2,
+        "G" => 3,
+        "T" => 4,
+        _ => return Err(format!("unknown size suffix '{suffix}' (expected K, M, G, or T)")),
+    };
+    match number.checked_mul(1024_u64.pow(power)) {
+        Some(0) => Err("the memory limit must be more than zero".to_string()),
+        Some(size) => Ok(size),
+        None => Err(format!("'{value}' is too large")),
+    }
+}
+
+/// The names used to report provenance: `<stdin>` for standard input, and the
+/// path as given on the command line otherwise.
+fn operand_names(paths: &[PathBuf]) -> Vec<String> {
+    if paths.is_empty() {
+        return vec!["<stdin>".to_string()];
+    }
+    paths
+        .iter()
+        .map(|p| if p.as_os_str() == "-" { "<stdin>".to_string() } else { p.display().to_string() })
+        .collect()
 }
 
 fn help_and_exit() -> ! {
@@ -66,6 +226,36 @@ fn help_and_exit() -> ! {
     exit_success();
 }
 
+/// `zet index build SOURCE -o INDEX` is the only `index` command. Remove the
+/// word `build` from `paths`, leaving `SOURCE`, or exit with a usage error.
+/// The options that don't apply to building an index (all but `-o`,
+/// `--color`, and `--verbose`) are usage errors too.
+fn check_index_build(paths: &mut Vec<PathBuf>, has_output: bool, matches: &ArgMatches) {
+    if paths.first().is_none_or(|word| word.as_os_str() != "build") {
+        usage_error("the only index command is `zet index build SOURCE -o INDEX`")
+    }
+    paths.remove(0);
+    if paths.len() != 1 || !has_output {
+        usage_error("`zet index build` needs one SOURCE file, and -o INDEX")
+    }
+    let applies = ["output", "color", "verbose"];
+    let command = CliArgs::command();
+    let unwanted = |arg: &&Arg| {
+        let id = arg.get_id().as_str();
+        let given = matches.value_source(id) == Some(ValueSource::CommandLine);
+        given && !arg.is_positional() && !applies.contains(&id)
+    };
+    let option = command.get_arguments().find(unwanted).and_then(Arg::get_long);
+    if let Some(option) = option {
+        usage_error(&format!("`zet index build` doesn't take the --{option} option"))
+    }
+}
+
+/// Report a command-line error the way `clap` does, and exit
+fn usage_error(message: &str) -> ! {
+    CliArgs::command().error(clap::error::ErrorKind::InvalidValue, message).exit()
+}
+
 const SUCCESS_CODE: i32 = 0;
 fn exit_success() -> ! {
     safe_exit(SUCCESS_CODE)
@@ -82,32 +272,33 @@ fn safe_exit(code: i32) -> ! {
 
 pub struct Args {
     /// `op` is the set operation requested
-    pub op: OpName,
+    pub op: Operation,
+    /// Rather than calculating `op`, should we build an index of the single
+    /// file in `paths`, and write it to `output_file`? (`zet index build`)
+    pub build_index: bool,
     /// Should we count the number of times each line occurs?
-    pub log_type: LogType,
+    pub log_type: CountMode,
+    /// How should we format the output?
+    pub output: Output,
+    /// How should we read the input?
+    pub reading: Reading,
+    /// The position (counting from `1`) of the operand `--base` asks us to
+    /// read first, if any
+    pub base: Option<usize>,
+    /// Should we report peak memory use (and falling back to `--memory-limit`)
+    /// on standard error?
+    pub verbose: bool,
+    /// Should we recalculate the result whenever an operand changes?
+    pub watch: bool,
+    /// The file to write the result to, if not standard output
+    pub output_file: Option<PathBuf>,
+    /// Additional operations requested with `--emit`, each with the path to
+    /// write its result to (`-` for standard output)
+    pub emits: Vec<(Operation, PathBuf)>,
     /// `paths` is the list of files from the command line
     pub paths: Vec<PathBuf>,
 }
 
-/// Set operation to perform
-#[derive(PartialEq, Eq, Debug, Clone, Copy)]
-pub enum OpName {
-    /// Print the lines present in every file
-    Intersect,
-    /// Print the lines present in any file
-    Union,
-    /// Print the lines present in the first file but no other
-    Diff,
-    /// Print the lines present exactly once in the entire input
-    Single,
-    /// Print the lines present in exactly one file
-    SingleByFile,
-    /// Print the lines present more than once in the entire input
-    Multiple,
-    /// Print the lines present in two or more files
-    MultipleByFile,
-}
-
 #[derive(Debug, Parser)]
 #[command(name = "zet")]
 /// `CliArgs` contains the parsed command line.
@@ -137,6 +328,147 @@ struct CliArgs {
     /// `--lines` is the default. Specify it explicitly to override a previous `--files`
     lines: bool,
 
+    #[arg(long, value_name("FORMAT"))]
+    /// The `--output-format` flag chooses between plain text (the default),
+    /// a JSON array, or newline-delimited JSON
+    output_format: Option<OutputFormat>,
+
+    #[arg(
+        long,
+        value_name("TEMPLATE"),
+        value_parser(Template::parse),
+        conflicts_with("output_format")
+    )]
+    /// The `--format` flag writes each output line with a template such as
+    /// `{count_lines}\t{line}`
+    format: Option<Template>,
+
+    #[arg(long, value_name("COUNT"), conflicts_with_all(["output_format", "format"]))]
+    /// The `--histogram` flag reports how many result lines have each line
+    /// count (`lines`) or file count (`files`), rather than the lines themselves
+    histogram: Option<HistogramBy>,
+
+    #[arg(long, value_name("SCALE"), requires("histogram"))]
+    /// The `--buckets` flag groups `--histogram` counts one per count
+    /// (`linear`, the default) or by powers of ten (`log`)
+    buckets: Option<Buckets>,
+
+    #[arg(long)]
+    /// The `--provenance` flag tells `zet` to report the file and line number
+    /// where each output line first appeared
+    provenance: bool,
+
+    #[arg(long, value_name("WHERE"))]
+    /// The `--count-position` flag puts the count before (`prefix`, the
+    /// default) or after (`suffix`) the line
+    count_position: Option<CountPosition>,
+
+    #[arg(long, value_name("SEP"))]
+    /// The `--count-separator` flag sets the text separating the count from the
+    /// line (default: a single space)
+    count_separator: Option<String>,
+
+    #[arg(long, value_name("HOW"))]
+    /// The `--saturated` flag says how to report line counts too large to
+    /// report exactly: as the word `overflow` (the default), as `4294967295`,
+    /// or as an error
+    saturated: Option<Saturated>,
+
+    #[arg(long, value_name("ORDER"), value_parser(parse_sort))]
+    /// The `--sort` flag chooses the output order: `first-seen` (the default),
+    /// `last-seen` (short for `--order last-seen`), `lexical`, `natural`,
+    /// `count`, or `files`
+    sort: Option<(SortOrder, Sequence)>,
+
+    #[arg(long, value_name("SEQUENCE"))]
+    /// The `--order` flag places each line at its first appearance
+    /// (`first-seen`, the default) or its last appearance (`last-seen`)
+    order: Option<Sequence>,
+
+    #[arg(short, long)]
+    /// The `--reverse` flag reverses the output order
+    reverse: bool,
+
+    #[arg(long, value_name("N"), conflicts_with("bottom"))]
+    /// The `--top` flag keeps only the `N` most frequent lines
+    top: Option<usize>,
+
+    #[arg(long, value_name("N"))]
+    /// The `--bottom` flag keeps only the `N` least frequent lines
+    bottom: Option<usize>,
+
+    #[arg(short, long, value_name("PATH"))]
+    /// The `--output` flag writes the result to `PATH` rather than standard
+    /// output, replacing `PATH` only once the result is complete
+    output: Option<PathBuf>,
+
+    #[arg(long, value_name("OP=PATH"), value_parser(parse_emit))]
+    /// Each `--emit OP=PATH` flag writes the result of the `OP` command to
+    /// `PATH` as well, reading the input only once
+    emit: Vec<(CliName, PathBuf)>,
+
+    #[arg(long, value_name("N"))]
+    /// The `--threads` flag reads up to `N` operands at once (`0` means one
+    /// thread per processor). The result is the same as with one thread.
+    threads: Option<usize>,
+
+    #[arg(long, value_name("N"), value_parser(parse_position))]
+    #[arg(conflicts_with_all(["emit", "threads", "hash_only", "memory_limit", "sorted", "stream"]))]
+    /// The `--base` flag reads the `N`th operand first, rather than letting
+    /// `intersect` pick the smallest
+    base: Option<usize>,
+
+    #[arg(long)]
+    #[arg(conflicts_with_all([
+        "emit",
+        "threads",
+        "hash_only",
+        "memory_limit",
+        "sorted",
+        "sort",
+        "order",
+        "reverse"
+    ]))]
+    /// The `--stream` flag makes `union` write each line as soon as it's first
+    /// seen
+    stream: bool,
+
+    #[arg(long, conflicts_with_all(["emit", "threads", "hash_only", "memory_limit"]))]
+    /// The `--sorted` flag says that every operand is sorted, so we can merge
+    /// them in step, using almost no memory
+    sorted: bool,
+
+    #[arg(long, value_name("SIZE"), value_parser(parse_size))]
+    #[arg(conflicts_with_all(["emit", "threads", "hash_only"]))]
+    /// The `--memory-limit` flag spills the input to temporary files, keeping
+    /// roughly `SIZE` bytes of distinct lines in memory at a time
+    memory_limit: Option<u64>,
+
+    #[arg(long, value_name("SIZE"), value_parser(parse_size))]
+    #[arg(conflicts_with_all(["threads", "hash_only", "memory_limit", "sorted", "stream"]))]
+    /// The `--max-memory` flag stops `zet` with an error, or switches to
+    /// `--memory-limit`, when it would use more than about `SIZE` bytes
+    max_memory: Option<u64>,
+
+    #[arg(short, long)]
+    /// The `--verbose` flag reports peak memory use on standard error
+    verbose: bool,
+
+    #[arg(long, conflicts_with_all(["emit", "hash_only", "memory_limit", "sorted", "stream"]))]
+    /// The `--watch` flag recalculates the result whenever an operand changes,
+    /// until interrupted
+    watch: bool,
+
+    #[arg(long, conflicts_with_all(["emit", "threads"]))]
+    /// The `--hash-only` flag keeps a hash of each distinct line rather than
+    /// the line itself, and reads the input again to output the result
+    hash_only: bool,
+
+    #[arg(long, requires("hash_only"))]
+    /// The `--verify-hashes` flag makes `--hash-only` read the input an extra
+    /// time to check for hash collisions
+    verify_hashes: bool,
+
     #[arg(short, long)]
     /// Like the `help` command, the `-h` or `--help` flags tell us to print the help message
     /// and exit
@@ -173,6 +505,8 @@ enum CliName {
     Single,
     /// Print the lines present in two or more files
     Multiple,
+    /// Build an index of a file, for `diff` and `intersect` to look lines up in
+    Index,
     /// Print a help message
     Help,
 }
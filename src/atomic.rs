@@ -0,0 +1,130 @@
+//! Provides `AtomicFile`, used for the `--output` and `--emit` files. An
+//! `AtomicFile` writes to a temporary file in the same directory as its
+//! target, and renames the temporary file to the target only when `commit` is
+//! called. So the target is never truncated or partly written: it either keeps
+//! its old contents (if we fail, or are interrupted, before `commit`) or gets
+//! all of its new contents at once. This makes it safe for the target to be one
+//! of the input files — as in `zet union -o a.txt a.txt b.txt`.
+use anyhow::{Context, Result};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    process,
+};
+
+/// A file that replaces `target` only when `commit` is called. If an
+/// `AtomicFile` is dropped without being committed, its temporary file is
+/// removed.
+pub struct AtomicFile {
+    target: PathBuf,
+    temp: PathBuf,
+    out: Option<BufWriter<File>>,
+}
+
+impl AtomicFile {
+    /// Create a temporary file next to `target`. If `target` is a symbolic
+    /// link, the temporary file goes next to the file it links to, and will
+    /// replace that file rather than the link. If `target` already exists, the
+    /// temporary file gets the same permissions.
+    pub fn create(target: &Path) -> Result<Self> {
+        let target = fs::canonicalize(target).unwrap_or_else(|_| target.to_path_buf());
+        let cant_create = || format!("Can't create file: {}", target.display());
+        let file_name = target.file_name().with_context(cant_create)?.to_string_lossy();
+        let directory = match target.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+
+        let mut attempt = 0;
+        let (temp, file) = loop {
+            let temp = directory.join(format!(".{file_name}.zet-{}-{attempt}.tmp", process::id()));
+            match OpenOptions::new().write(true).create_new(true).open(&temp) {
+                Ok(file) => break (temp, file),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists && attempt < 100 => attempt += 1,
+                Err(e) => return Err(e).with_context(cant_create),
+            }
+        };
+        let permissions = fs::metadata(&target).map(|metadata| metadata.permissions());
+        let atomic_file =
+            AtomicFile { target: target.clone(), temp, out: Some(BufWriter::new(file)) };
+        if let Ok(permissions) = permissions {
+            fs::set_permissions(&atomic_file.temp, permissions).with_context(cant_create)?;
+        }
+        Ok(atomic_file)
+    }
+
+    /// Flush the temporary file to disk and rename it to the target.
+    pub fn commit(mut self) -> Result<()> {
+        let cant_write = || format!("Can't write file: {}", self.target.display());
+        if let Some(out) = self.out.take() {
+            let file = out.into_inner().map_err(io::IntoInnerError::into_error);
+            file.and_then(|file| file.sync_all()).with_context(cant_write)?;
+        }
+        fs::rename(&self.temp, &self.target).with_context(cant_write)?;
+        self.temp = PathBuf::new(); // So `drop` has nothing to remove
+        Ok(())
+    }
+
+    fn out(&mut self) -> &mut BufWriter<File> {
+        self.out.as_mut().expect("AtomicFile used after commit")
+    }
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.out().write(buf)
+    }
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.out().write_all(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.out().flush()
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        drop(self.out.take());
+        if !self.temp.as_os_str().is_empty() {
+            let _ = fs::remove_file(&self.temp);
+        }
+    }
+}
+
+#[allow(clippy::pedantic)]
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assert_fs::{prelude::*, TempDir};
+
+    fn entries(dir: &TempDir) -> usize {
+        fs::read_dir(dir.path()).unwrap().count()
+    }
+
+    #[test]
+    fn the_target_is_unchanged_until_commit() {
+        let dir = TempDir::new().unwrap();
+        let target = dir.child("a.txt");
+        target.write_str("old\n").unwrap();
+        let mut file = AtomicFile::create(target.path()).unwrap();
+        file.write_all(b"new\n").unwrap();
+        file.flush().unwrap();
+        target.assert("old\n");
+        file.commit().unwrap();
+        target.assert("new\n");
+        assert_eq!(entries(&dir), 1);
+    }
+
+    #[test]
+    fn dropping_without_commit_leaves_the_target_alone() {
+        let dir = TempDir::new().unwrap();
+        let target = dir.child("a.txt");
+        target.write_str("old\n").unwrap();
+        let mut file = AtomicFile::create(target.path()).unwrap();
+        file.write_all(b"partial").unwrap();
+        drop(file);
+        target.assert("old\n");
+        assert_eq!(entries(&dir), 1);
+    }
+}
@@ -0,0 +1,183 @@
+//! Provides `Budget`, which keeps `calculate` within the memory allowed by
+//! `--max-memory`, and `peak_memory`, which `--verbose` reports.
+//!
+//! We can't ask the allocator how much memory a `ZetSet` holds, but we can
+//! come close: the first operand's bytes, the bytes of the arena its later
+//! lines are copied into, and the `IndexMap`'s entries, at the map's capacity
+//! rather than its length. (`ZetSet::memory_used` adds up the last two.) When
+//! that total goes past the limit, we stop adding lines and return an
+//! `OverBudget` error, rather than being killed by the operating system
+//! without explanation.
+use anyhow::{bail, Result};
+use std::fmt;
+
+/// How much memory `calculate` may use, and how much it's using. Without a
+/// limit, a `Budget` still keeps count, but is never exceeded.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Budget {
+    limit: u64,
+    /// Bytes held outside the `ZetSet` — the first operand's contents
+    held: u64,
+    /// Bytes held by the `ZetSet`, when last charged
+    set: u64,
+    /// Bytes of input read so far, line terminators included
+    read: u64,
+}
+impl Budget {
+    /// A `Budget` of `limit` bytes, or an unlimited one
+    pub(crate) fn new(limit: Option<u64>) -> Self {
+        Budget { limit: limit.unwrap_or(u64::MAX), held: 0, set: 0, read: 0 }
+    }
+
+    /// Count `bytes` held outside the `ZetSet` — the first operand's contents
+    pub(crate) fn hold(&mut self, bytes: usize) {
+        self.held = self.held.saturating_add(bytes as u64);
+    }
+
+    /// Count a line of `bytes` bytes (not including its terminator) as read
+    pub(crate) fn read(&mut self, bytes: usize) {
+        self.read = self.read.saturating_add(bytes as u64 + 1);
+    }
+
+    /// Record that the `ZetSet` now holds `bytes` bytes
+    pub(crate) fn charge(&mut self, bytes: u64) {
+        self.set = bytes;
+    }
+
+    /// The bytes in use
+    fn used(&self) -> u64 {
+        self.held.saturating_add(self.set)
+    }
+
+    /// Are we using more memory than we're allowed?
+    pub(crate) fn exceeded(&self) -> bool {
+        self.used() > self.limit
+    }
+
+    /// Return an `OverBudget` error if we're using more memory than we're
+    /// allowed. `operand` is the command-line position (counting from `0`) of
+    /// the operand we were reading.
+    pub(crate) fn check(&self, operand: u32) -> Result<()> {
+        if self.exceeded() {
+            let Budget { limit, held, set, read } = *self;
+            bail!(OverBudget { operand, limit, held, set, read })
+        }
+        Ok(())
+    }
+}
+
+/// The error returned when `calculate` would use more memory than its
+/// `Reading::max_memory` allows. It reports how much was in use when we
+/// stopped, and lets the caller estimate how much the whole input would need.
+#[derive(Clone, Copy, Debug)]
+pub struct OverBudget {
+    /// The command-line position (counting from `0`) of the operand we were
+    /// reading when we stopped
+    pub operand: u32,
+    /// The memory limit, in bytes
+    pub limit: u64,
+    held: u64,
+    set: u64,
+    read: u64,
+}
+impl OverBudget {
+    /// The bytes in use when we stopped
+    #[must_use]
+    pub fn used(&self) -> u64 {
+        self.held.saturating_add(self.set)
+    }
+
+    /// An estimate of the bytes needed to read `input_size` bytes of input,
+    /// assuming the `ZetSet` would keep growing as it had been
+    #[must_use]
+    pub fn estimate(&self, input_size: u64) -> u64 {
+        if self.read == 0 || input_size <= self.read {
+            return self.used();
+        }
+        let scaled = u128::from(self.set) * u128::from(input_size) / u128::from(self.read);
+        self.held.saturating_add(u64::try_from(scaled).unwrap_or(u64::MAX))
+    }
+}
+impl fmt::Display for OverBudget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Operand {} took zet past its memory limit of {} ({} in use)",
+            self.operand + 1,
+            format_size(self.limit),
+            format_size(self.used())
+        )
+    }
+}
+impl std::error::Error for OverBudget {}
+
+/// Format `bytes` for people: `512 bytes`, `1.5 KiB`, `2.0 GiB`, and so on
+#[must_use]
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+    if bytes < 1024 {
+        return format!("{bytes} bytes");
+    }
+    #[allow(clippy::cast_precision_loss)] // We show just one decimal place
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
+
+/// The most memory this process has had resident at once, in bytes, if the
+/// operating system will tell us. (On Linux, this is `VmHWM` in
+/// `/proc/self/status`.)
+#[must_use]
+pub fn peak_memory() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find_map(|line| line.strip_prefix("VmHWM:"))?;
+    let kib = line.trim().strip_suffix("kB")?.trim().parse::<u64>().ok()?;
+    Some(kib * 1024)
+}
+
+#[allow(clippy::pedantic)]
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn budget_is_exceeded_only_past_the_limit() {
+        let mut budget = Budget::new(Some(100));
+        budget.hold(60);
+        budget.charge(40);
+        assert!(budget.check(0).is_ok());
+        budget.charge(41);
+        let error = budget.check(2).unwrap_err();
+        let over = error.downcast_ref::<OverBudget>().unwrap();
+        assert_eq!((over.operand, over.used()), (2, 101));
+
+        let mut unlimited = Budget::new(None);
+        unlimited.charge(u64::MAX);
+        assert!(!unlimited.exceeded());
+    }
+
+    #[test]
+    fn estimate_scales_the_set_but_not_the_first_operand() {
+        let mut budget = Budget::new(Some(10));
+        budget.hold(1000);
+        budget.charge(200);
+        for _ in 0..50 {
+            budget.read(1); // 100 bytes, counting line terminators
+        }
+        let error = budget.check(0).unwrap_err();
+        let over = error.downcast_ref::<OverBudget>().unwrap();
+        assert_eq!(over.estimate(1000), 1000 + 200 * 10);
+        assert_eq!(over.estimate(50), 1200);
+    }
+
+    #[test]
+    fn sizes_are_formatted_in_binary_units() {
+        assert_eq!(format_size(512), "512 bytes");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(2 << 30), "2.0 GiB");
+    }
+}
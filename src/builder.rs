@@ -0,0 +1,498 @@
+//! Provides `SetBuilder`, for using `zet`'s set engine as a library. A
+//! `SetBuilder` collects the lines of its operands, and the `SetResult` it
+//! returns lets the caller iterate over the lines of the result and their
+//! counts. To write the result as the `zet` command would, use a
+//! `Calculation`. To keep state of your own for each line, and decide which
+//! lines to keep by it, use an `Aggregation`.
+//!
+//! The library doesn't need the command-line parser: with
+//! `default-features = false`, the `cli` feature is off, and `zet` doesn't
+//! depend on `clap` or the crates the `zet` command uses to decide whether to
+//! color its output.
+//!
+//! This module is the library's stable interface: it follows semantic
+//! versioning, so nothing here — including the items it re-exports, such as
+//! the `LaterOperand` and `Aggregate` traits (but not `LaterOperand`'s
+//! hidden `index` method, which only `zet`'s own operands implement) — will
+//! be removed or changed incompatibly before `zet` 2.0. The other public modules exist to serve the
+//! `zet` command, and may change in any release. Their types can be used with
+//! this module where they implement its traits (`operands::first_and_rest`
+//! reads files as the `zet` command does, returning `LaterOperand`s), but a
+//! program that names them may need changes in a later 1.x release.
+//!
+//! ```
+//! use zet::builder::{Operation, SetBuilder};
+//!
+//! let mut builder = SetBuilder::new(b"apple\nbanana\ncherry\n");
+//! builder.add_lines(["cherry", "banana", "cherry"])?;
+//! builder.add_lines(["cherry", "date"])?;
+//!
+//! let result = builder.calculate(Operation::Intersect);
+//! let lines: Vec<&[u8]> = result.iter().map(|entry| entry.line()).collect();
+//! assert_eq!(lines, [b"cherry"]);
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+use crate::budget::Budget;
+use crate::operations::{self, belongs_in, calculate, record, Bookkeeping, Reading, Tallied};
+use crate::output::{Output, Record};
+use crate::set::ZetSet;
+use anyhow::{bail, Result};
+use std::ops::ControlFlow;
+
+pub use crate::operations::{CountMode, Operation};
+pub use crate::output::Provenance;
+pub use crate::set::{Aggregate, LaterOperand};
+// Renamed, since `Lines` here is an operand, and `LineCount` is what it counts
+pub use crate::operations::{Files as FileCount, Lines as LineCount, Unsifted};
+
+/// The bookkeeping kept for each line. As for `calculate_many`, we keep every
+/// line of the input, with enough information to decide membership for any
+/// operation.
+type Item = Tallied<Unsifted>;
+
+/// Collects the lines of a set operation's operands. The first operand's lines
+/// are borrowed from it; later operands' lines are copied.
+///
+/// As with the `zet` command, lines are separated by `\n`, and a `\r` before
+/// the `\n` and a UTF-8 Byte Order Mark at the start of the first operand
+/// aren't part of any line. (Unlike the `zet` command, a `SetBuilder` doesn't
+/// translate UTF-16.)
+///
+/// Since the operation isn't chosen until the end, every distinct line of the
+/// input is kept in memory, even for `Diff` and `Intersect`, whose results
+/// hold only lines of the first operand.
+pub struct SetBuilder<'data> {
+    set: ZetSet<'data, Item>,
+    item: Item,
+    operands: u32,
+}
+
+impl<'data> SetBuilder<'data> {
+    /// Start a set with the lines of `first_operand`
+    #[must_use]
+    pub fn new(first_operand: &'data [u8]) -> Self {
+        let item = Item::new();
+        let set = ZetSet::new(first_operand, item, &mut Budget::new(None));
+        SetBuilder { set, item, operands: 1 }
+    }
+
+    /// Add an operand: any `LaterOperand`. An index (a `.zidx` file) can't be
+    /// added, since its lines can't be read.
+    ///
+    /// Here the operands are files, read as the `zet` command reads them, by
+    /// `operands::first_and_rest` — which, unlike this module, isn't covered by
+    /// semantic versioning.
+    ///
+    /// ```
+    /// use zet::builder::{Operation, SetBuilder};
+    /// use zet::operands::first_and_rest;
+    /// # let dir = assert_fs::TempDir::new()?;
+    /// # let (a, b) = (dir.path().join("a.txt"), dir.path().join("b.txt"));
+    /// # std::fs::write(&a, "x\ny\n")?;
+    /// # std::fs::write(&b, "y\nz\n")?;
+    ///
+    /// let (first, rest) = first_and_rest(&[a, b]).unwrap();
+    /// let first = first?;
+    /// let mut builder = SetBuilder::new(first.as_slice());
+    /// for operand in rest {
+    ///     builder.add_operand(operand?)?;
+    /// }
+    /// let result = builder.calculate(Operation::Union);
+    /// assert_eq!(result.len(), 3);
+    /// # Ok::<(), anyhow::Error>(())
+    /// ```
+    pub fn add_operand(&mut self, operand: impl LaterOperand) -> Result<&mut Self> {
+        if self.operands == u32::MAX {
+            bail!("Zet can't handle more than {} operands", u32::MAX)
+        }
+        self.operands += 1;
+        self.item.next_file();
+        self.set.insert_or_update(operand, None, &mut Budget::new(None), self.item)?;
+        Ok(self)
+    }
+
+    /// Add an operand whose lines are the items of `lines`, each without a
+    /// line terminator
+    pub fn add_lines<L: AsRef<[u8]>>(
+        &mut self,
+        lines: impl IntoIterator<Item = L>,
+    ) -> Result<&mut Self> {
+        self.add_operand(Lines(lines))
+    }
+
+    /// Calculate `operation` on the operands added so far. The lines of the
+    /// result are in the order they first appeared in the input.
+    #[must_use]
+    pub fn calculate(self, operation: Operation) -> SetResult<'data> {
+        let SetBuilder { mut set, operands, .. } = self;
+        let keep: Vec<bool> =
+            set.values().map(|&item| belongs_in(operation, item, operands)).collect();
+        set.retain_indices(&keep);
+        SetResult { set }
+    }
+}
+
+/// An operand whose lines are the items of an iterator (or array, or `Vec`,
+/// and so on), each without a line terminator
+pub struct Lines<I>(pub I);
+impl<L: AsRef<[u8]>, I: IntoIterator<Item = L>> LaterOperand for Lines<I> {
+    fn for_byte_line(self, mut for_each_line: impl FnMut(&[u8])) -> Result<()> {
+        for line in self.0 {
+            for_each_line(line.as_ref());
+        }
+        Ok(())
+    }
+
+    fn try_for_byte_line(
+        self,
+        mut for_each_line: impl FnMut(&[u8]) -> ControlFlow<()>,
+    ) -> Result<()> {
+        for line in self.0 {
+            if for_each_line(line.as_ref()).is_break() {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The result of a set operation, returned by `SetBuilder::calculate`
+pub struct SetResult<'data> {
+    set: ZetSet<'data, Item>,
+}
+
+impl SetResult<'_> {
+    /// The number of lines in the result
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.set.len()
+    }
+
+    /// Is the result empty?
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.set.len() == 0
+    }
+
+    /// The lines of the result, in the order they first appeared in the input
+    pub fn iter(&self) -> impl Iterator<Item = Entry<'_>> {
+        self.set.entries().map(|(line, item)| Entry(record(line, item)))
+    }
+
+    /// The Byte Order Mark of the first operand: `b"\xEF\xBB\xBF"`, or `b""`
+    /// if it had none. The `zet` command starts its output with it.
+    #[must_use]
+    pub fn bom(&self) -> &'static [u8] {
+        self.set.bom
+    }
+
+    /// The line terminator of the first operand's first line: `b"\r\n"` or
+    /// `b"\n"`. The `zet` command ends each line of its output with it.
+    #[must_use]
+    pub fn line_terminator(&self) -> &'static [u8] {
+        self.set.line_terminator
+    }
+}
+
+/// A line of a result, with its counts and where it first appeared. (Both
+/// `SetResult::iter` and `Calculation::for_each` keep track of all three, so
+/// the `Record` an `Entry` wraps has every field.)
+#[derive(Clone, Copy, Debug)]
+pub struct Entry<'a>(Record<'a>);
+
+impl<'a> Entry<'a> {
+    /// The line, without its line terminator
+    #[must_use]
+    pub fn line(&self) -> &'a [u8] {
+        self.0.line
+    }
+
+    /// The number of times the line appeared in the input (at most
+    /// `u32::MAX`; larger counts are reported as `u32::MAX`)
+    #[must_use]
+    pub fn line_count(&self) -> u32 {
+        self.0.count_lines.unwrap_or_default()
+    }
+
+    /// The number of operands the line appeared in
+    #[must_use]
+    pub fn file_count(&self) -> u32 {
+        self.0.count_files.unwrap_or_default()
+    }
+
+    /// Where the line first appeared: the operand (counting from `0`, in the
+    /// order the operands were given) and the line number within it
+    /// (counting from `1`)
+    #[must_use]
+    pub fn first_seen(&self) -> Provenance {
+        self.0.provenance.unwrap_or(Provenance { file_number: 0, lineno: 0 })
+    }
+}
+
+/// Calculates a set operation, and either writes the result as the `zet`
+/// command would — a line at a time, each with its count if a `CountMode`
+/// other than `CountMode::None` is chosen — or passes each line of the result
+/// to a closure. Unlike a `SetBuilder`, a `Calculation` keeps only the lines
+/// the operation needs.
+///
+/// ```
+/// use zet::builder::{Calculation, CountMode, Lines, Operation};
+///
+/// let rest = [Lines(["b", "c"])].map(Ok);
+/// let mut out = Vec::new();
+/// Calculation::new(Operation::Union)
+///     .count(CountMode::Lines)
+///     .write(b"a\nb\nb\n", rest.into_iter(), &mut out)?;
+/// assert_eq!(out, b"1 a\n3 b\n1 c\n");
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[derive(Clone, Copy, Debug)]
+pub struct Calculation {
+    operation: Operation,
+    count: CountMode,
+    threads: usize,
+}
+
+impl Calculation {
+    /// A calculation of `operation`, with no counts, reading one operand at a
+    /// time
+    #[must_use]
+    pub fn new(operation: Operation) -> Self {
+        Calculation { operation, count: CountMode::None, threads: 1 }
+    }
+
+    /// Write each line's count, as chosen by `count`
+    #[must_use]
+    pub fn count(self, count: CountMode) -> Self {
+        Calculation { count, ..self }
+    }
+
+    /// Read up to `threads` of the operands after the first at once. The
+    /// result is the same as reading them one at a time.
+    #[must_use]
+    pub fn threads(self, threads: usize) -> Self {
+        Calculation { threads: threads.max(1), ..self }
+    }
+
+    /// Calculate the operation on `first_operand` and `rest`, and write the
+    /// result to `out`
+    pub fn write<O: LaterOperand>(
+        &self,
+        first_operand: &[u8],
+        rest: impl ExactSizeIterator<Item = Result<O>> + Send,
+        out: impl std::io::Write,
+    ) -> Result<()> {
+        let reading = Reading { threads: self.threads, ..Reading::default() };
+        let output = Output::default();
+        calculate(self.operation, self.count, &output, &reading, first_operand, rest, out)
+    }
+
+    /// Calculate the operation on `first_operand` and `rest`, and call `visit`
+    /// with each line of the result, in the order the lines first appeared in
+    /// the input. The `CountMode` is ignored: every `Entry` has both counts.
+    ///
+    /// ```
+    /// use zet::builder::{Calculation, Lines, Operation};
+    ///
+    /// let rest = [Lines(["b", "c", "b"])].map(Ok);
+    /// let mut multiple = Vec::new();
+    /// Calculation::new(Operation::Multiple).for_each(b"a\nb\n", rest.into_iter(), |entry| {
+    ///     multiple.push((entry.line().to_vec(), entry.line_count(), entry.file_count()));
+    /// })?;
+    /// assert_eq!(multiple, [(b"b".to_vec(), 3, 2)]);
+    /// # Ok::<(), anyhow::Error>(())
+    /// ```
+    pub fn for_each<O: LaterOperand>(
+        &self,
+        first_operand: &[u8],
+        rest: impl ExactSizeIterator<Item = Result<O>> + Send,
+        mut visit: impl FnMut(Entry<'_>),
+    ) -> Result<()> {
+        let reading = Reading { threads: self.threads, ..Reading::default() };
+        let visit = |record: Record<'_>| visit(Entry(record));
+        operations::visit(self.operation, &reading, first_operand, rest, visit)
+    }
+}
+
+/// A set of lines, each with state of the caller's choosing: an `Aggregate`.
+/// Each operand is added with a value of the `Aggregate` type; a line seen
+/// for the first time gets that value as its state, and a line seen again has
+/// its state updated with it (see `Aggregate::update_with`). Lines are read as
+/// they are by a `SetBuilder`, and kept in the order they first appeared.
+///
+/// The `zet` command's own counts are `Aggregate`s too, so they can be part of
+/// a caller's state: `LineCount` counts a line's appearances, `FileCount` the
+/// operands it appears in, and `Unsifted` keeps nothing at all.
+///
+/// ```
+/// use zet::builder::{Aggregate, Aggregation};
+///
+/// /// The latest timestamp of the operands a line appears in
+/// #[derive(Clone, Copy)]
+/// struct Latest(u64);
+/// impl Aggregate for Latest {
+///     fn update_with(&mut self, other: Self) {
+///         self.0 = self.0.max(other.0);
+///     }
+/// }
+///
+/// let mut lines = Aggregation::new(b"a\nb\n", Latest(100));
+/// lines.add_lines(["b", "c"], Latest(200))?.add_lines(["a"], Latest(50))?;
+/// lines.retain(|_, latest| latest.0 >= 200);
+/// let kept: Vec<(&[u8], u64)> = lines.iter().map(|(line, latest)| (line, latest.0)).collect();
+/// assert_eq!(kept, [(&b"b"[..], 200), (&b"c"[..], 200)]);
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+pub struct Aggregation<'data, A: Aggregate> {
+    set: ZetSet<'data, A>,
+}
+
+impl<'data, A: Aggregate> Aggregation<'data, A> {
+    /// Start a set with the lines of `first_operand`, each with state `item`
+    #[must_use]
+    pub fn new(first_operand: &'data [u8], item: A) -> Self {
+        Aggregation { set: ZetSet::new(first_operand, item, &mut Budget::new(None)) }
+    }
+
+    /// Add an operand, with `item` for the state of its lines. As for
+    /// `SetBuilder::add_operand`, an index can't be added.
+    pub fn add_operand(&mut self, operand: impl LaterOperand, item: A) -> Result<&mut Self> {
+        self.set.insert_or_update(operand, None, &mut Budget::new(None), item)?;
+        Ok(self)
+    }
+
+    /// Add an operand whose lines are the items of `lines`, each without a
+    /// line terminator, with `item` for the state of its lines
+    pub fn add_lines<L: AsRef<[u8]>>(
+        &mut self,
+        lines: impl IntoIterator<Item = L>,
+        item: A,
+    ) -> Result<&mut Self> {
+        self.add_operand(Lines(lines), item)
+    }
+
+    /// Keep only the lines for which `keep` returns `true`, given each line
+    /// and its state
+    pub fn retain(&mut self, mut keep: impl FnMut(&[u8], A) -> bool) {
+        let keep: Vec<bool> = self.set.entries().map(|(line, item)| keep(line, item)).collect();
+        self.set.retain_indices(&keep);
+    }
+
+    /// The number of lines in the set
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.set.len()
+    }
+
+    /// Is the set empty?
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.set.len() == 0
+    }
+
+    /// The lines of the set, each with its state, in the order they first
+    /// appeared in the input
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], A)> {
+        self.set.entries()
+    }
+}
+
+#[allow(clippy::pedantic)]
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::operations::Operation::*;
+    use crate::operations::{calculate, CountMode, Reading};
+    use crate::output::Output;
+
+    #[test]
+    fn a_set_result_has_the_lines_and_counts_calculate_writes() {
+        let operands: Vec<&[u8]> = vec![b"a\nb\nb\nc\n", b"b\nd\nd\n", b"b\ne\nc\n"];
+        for op in [Intersect, Union, Diff, Single, SingleByFile, Multiple, MultipleByFile] {
+            let mut builder = SetBuilder::new(operands[0]);
+            for &operand in &operands[1..] {
+                builder.add_operand(operand).unwrap();
+            }
+            let result = builder.calculate(op);
+            let mut from_builder = String::new();
+            for entry in result.iter() {
+                let line = String::from_utf8_lossy(entry.line());
+                from_builder += &format!("{} {line}\n", entry.line_count());
+            }
+
+            let rest = operands[1..].iter().map(|o| Ok(*o));
+            let (output, reading) = (Output::default(), Reading::default());
+            let mut answer = Vec::new();
+            calculate(op, CountMode::Lines, &output, &reading, operands[0], rest, &mut answer)
+                .unwrap();
+            let answer = String::from_utf8(answer).unwrap();
+            let answer: String =
+                answer.lines().map(|line| line.trim_start().to_string() + "\n").collect();
+            assert_eq!(from_builder, answer, "for {op:?}");
+        }
+    }
+
+    #[test]
+    fn for_each_sees_what_a_set_result_holds() {
+        let operands: Vec<&[u8]> = vec![b"a\nb\nb\nc\n", b"b\nd\nd\n", b"b\ne\nc\n"];
+        let entry = |e: Entry| (e.line().to_vec(), e.line_count(), e.file_count(), e.first_seen());
+        for op in [Intersect, Union, Diff, Single, SingleByFile, Multiple, MultipleByFile] {
+            let mut builder = SetBuilder::new(operands[0]);
+            for &operand in &operands[1..] {
+                builder.add_operand(operand).unwrap();
+            }
+            let expected: Vec<_> = builder.calculate(op).iter().map(entry).collect();
+            let mut visited = Vec::new();
+            let rest = operands[1..].iter().map(|o| Ok(*o));
+            Calculation::new(op).for_each(operands[0], rest, |e| visited.push(entry(e))).unwrap();
+            assert_eq!(visited, expected, "for {op:?}");
+        }
+        let mut first_seen = Vec::new();
+        let rest = operands[1..].iter().map(|o| Ok(*o));
+        Calculation::new(Union)
+            .for_each(operands[0], rest, |e| first_seen.push(e.first_seen()))
+            .unwrap();
+        let position = |file_number, lineno| Provenance { file_number, lineno };
+        assert_eq!(
+            first_seen,
+            [position(0, 1), position(0, 2), position(0, 4), position(1, 2), position(2, 2)]
+        );
+    }
+
+    #[test]
+    fn an_aggregation_of_builtin_counts_matches_a_set_builder() {
+        #[derive(Clone, Copy)]
+        struct Counts {
+            lines: LineCount,
+            files: FileCount,
+            tags: u64,
+        }
+        impl Aggregate for Counts {
+            fn update_with(&mut self, other: Self) {
+                self.lines.update_with(other.lines);
+                self.files.update_with(other.files);
+                self.tags |= other.tags;
+            }
+        }
+        let counts =
+            |n| Counts { lines: LineCount::once(), files: FileCount::in_operand(n), tags: 1 << n };
+
+        let operands: Vec<&[u8]> = vec![b"a\nb\nb\nc\n", b"b\nd\nd\n", b"b\ne\nc\n"];
+        let mut aggregation = Aggregation::new(operands[0], counts(0));
+        let mut builder = SetBuilder::new(operands[0]);
+        for (n, &operand) in (1..).zip(&operands[1..]) {
+            aggregation.add_operand(operand, counts(n)).unwrap();
+            builder.add_operand(operand).unwrap();
+        }
+        let result = builder.calculate(Union);
+        let expected: Vec<_> =
+            result.iter().map(|e| (e.line(), e.line_count(), e.file_count())).collect();
+        let aggregated: Vec<_> =
+            aggregation.iter().map(|(line, c)| (line, c.lines.count(), c.files.count())).collect();
+        assert_eq!(aggregated, expected);
+
+        aggregation.retain(|_, c| c.tags == 0b101);
+        let lines: Vec<&[u8]> = aggregation.iter().map(|(line, _)| line).collect();
+        assert_eq!(lines, [b"c"]);
+    }
+}
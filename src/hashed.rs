@@ -0,0 +1,231 @@
+//! Provides `calculate_hashed`, used for `--hash-only`. Rather than a `ZetSet`,
+//! which keeps the bytes of every distinct line, `calculate_hashed` keeps a
+//! 128-bit hash of each distinct line, together with its counts. That takes
+//! much less memory when lines are long, but since we don't keep the lines
+//! themselves, we must read the input a second time to output them: on the
+//! second pass, we output each line whose hash belongs in the result the first
+//! time we see it. So every operand must be a file that can be read twice —
+//! except that `Union` without counts writes each line as soon as it's first
+//! seen, in a single pass.
+//!
+//! Two different lines with the same hash would be counted as the same line.
+//! That's very unlikely, but if asked to verify, we make an additional pass
+//! over the input before writing anything, checking that all the lines with a
+//! given hash also have the same second, independently-seeded hash.
+use crate::operands::{first_with_output_info, Remaining};
+use crate::operations::CountMode;
+use crate::operations::Operation::{
+    self, Diff, Intersect, Multiple, MultipleByFile, Single, SingleByFile, Union,
+};
+use crate::output::Output;
+use crate::set::LaterOperand;
+use anyhow::{bail, Result};
+use fxhash::FxHashMap;
+use std::collections::hash_map::Entry;
+use std::io::{self, Write};
+use std::iter;
+use std::mem;
+use std::ops::ControlFlow;
+use std::path::PathBuf;
+use xxhash_rust::xxh3::{xxh3_128, xxh3_128_with_seed};
+
+/// What we keep for each distinct line, in place of a `ZetSet`'s bookkeeping
+/// item. (The `spill` module uses it too.)
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Counts {
+    /// The number of times the line has been seen (saturating at `u32::MAX`)
+    pub(crate) lines: u32,
+    /// The number of files the line has been seen in
+    pub(crate) files: u32,
+    /// The number of the file the line was last seen in
+    pub(crate) file_number: u32,
+    /// Has the line been written yet?
+    pub(crate) written: bool,
+}
+impl Counts {
+    /// The counts for a line not yet seen, first seen in `file_number`
+    pub(crate) fn new(file_number: u32) -> Self {
+        Counts { lines: 0, files: 0, file_number, written: false }
+    }
+    /// Note another occurrence of the line, in `file_number`
+    pub(crate) fn see(&mut self, file_number: u32) {
+        self.lines = self.lines.saturating_add(1);
+        if self.files == 0 || self.file_number != file_number {
+            self.files += 1;
+            self.file_number = file_number;
+        }
+    }
+    /// The count to report for `log_type`, if any
+    pub(crate) fn count(self, log_type: CountMode) -> Option<u32> {
+        match log_type {
+            CountMode::Lines => Some(self.lines),
+            CountMode::Files => Some(self.files),
+            CountMode::None => None,
+        }
+    }
+}
+
+/// The distinct lines seen so far, identified by their hashes
+type HashedSet = FxHashMap<u128, Counts>;
+
+/// The seed for the second hash used to verify that there were no collisions
+const VERIFY_SEED: u64 = 0x7a65_745f_7665_7269; // "zet_veri"
+
+/// Calculate `operation` over the files in `paths`, as `calculate` would, but
+/// keeping only a hash of each line. We support only the plain text output
+/// format, with or without counts, in first-appearance order. If `verify` is
+/// set, we check for hash collisions before writing anything, and fail if we
+/// find one.
+pub fn calculate_hashed(
+    operation: Operation,
+    log_type: CountMode,
+    output: &Output,
+    paths: &[PathBuf],
+    verify: bool,
+    mut out: impl Write,
+) -> Result<()> {
+    if output.needs_tally() || output.reorders() {
+        bail!(
+            "--hash-only supports only plain text output, with or without counts, in the order \
+             lines first appear"
+        )
+    }
+    let Some((first, rest)) = paths.split_first() else {
+        bail!("--hash-only needs at least one input file")
+    };
+    let all_files = u32::try_from(paths.len())?;
+
+    // With `Diff` and `Intersect`, only lines of the first operand can be in
+    // the result, so those are the only lines we keep, and the only ones we
+    // need to read again.
+    let additive = !matches!(operation, Diff | Intersect);
+    let streaming = operation == Union && matches!(log_type, CountMode::None) && !verify;
+    let reread = if additive { paths } else { &paths[..1] };
+    if !streaming && reread.iter().any(|path| path.as_os_str() == "-") {
+        bail!("--hash-only can't read standard input twice")
+    }
+
+    // As `--stream` does, we take the Byte Order Mark and line terminator
+    // from the first line, so standard input gets them right too
+    let (first, bom, line_terminator) = first_with_output_info(first)?;
+    out.write_all(bom)?;
+    let mut set = HashedSet::default();
+    let mut written: io::Result<()> = Ok(());
+    let operands = iter::once(Ok(first)).chain(Remaining::from(rest.to_vec()));
+    for (file_number, operand) in operands.enumerate() {
+        let file_number = u32::try_from(file_number)?;
+        operand?.for_byte_line(|line| {
+            let counts = match set.entry(xxh3_128(line)) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) if additive || file_number == 0 => {
+                    let fresh = Counts { written: streaming, ..Counts::new(file_number) };
+                    if streaming && written.is_ok() {
+                        written = write_line(&mut out, None, line, line_terminator);
+                    }
+                    entry.insert(fresh)
+                }
+                Entry::Vacant(_) => return,
+            };
+            counts.see(file_number);
+        })?;
+        mem::replace(&mut written, Ok(()))?;
+    }
+    if streaming {
+        out.flush()?;
+        return Ok(());
+    }
+
+    if verify {
+        verify_hashes(&set, paths)?;
+    }
+    set.retain(|_, counts| belongs_in(operation, *counts, all_files));
+    let count_text = |counts: Counts| counts.count(log_type).map(|c| output.line_count_text(c));
+    let width = set.values().filter_map(|&c| count_text(c)).map(|c| c.len()).max();
+    let width = width.unwrap_or(0);
+    for operand in Remaining::from(reread.to_vec()) {
+        operand?.try_for_byte_line(|line| {
+            if written.is_err() {
+                return ControlFlow::Break(());
+            }
+            let Some(counts) = set.get_mut(&xxh3_128(line)) else {
+                return ControlFlow::Continue(());
+            };
+            if !counts.written {
+                counts.written = true;
+                let count = count_text(*counts).map(|count| format!("{count:>width$} "));
+                written = write_line(&mut out, count, line, line_terminator);
+            }
+            ControlFlow::Continue(())
+        })?;
+        mem::replace(&mut written, Ok(()))?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Write `line`, preceded by its `count` (if any) and followed by
+/// `line_terminator`
+pub(crate) fn write_line(
+    out: &mut impl Write,
+    count: Option<String>,
+    line: &[u8],
+    line_terminator: &[u8],
+) -> io::Result<()> {
+    if let Some(count) = count {
+        out.write_all(count.as_bytes())?;
+    }
+    out.write_all(line)?;
+    out.write_all(line_terminator)
+}
+
+/// The same decision as the `belongs_in` function that `calculate_many` uses:
+/// does a line with these `counts` belong in the result of `operation`? (For
+/// `Diff`, we keep only lines of the first operand, so a line seen in just one
+/// file was seen only there.)
+pub(crate) fn belongs_in(operation: Operation, counts: Counts, all_files: u32) -> bool {
+    match operation {
+        Union => true,
+        Diff | SingleByFile => counts.files == 1,
+        Intersect => counts.files == all_files,
+        Single => counts.lines == 1,
+        Multiple => counts.lines > 1,
+        MultipleByFile => counts.files > 1,
+    }
+}
+
+/// Read the files in `paths` again, checking that every line whose hash is in
+/// `set` has the same second hash as the first line seen with that hash.
+fn verify_hashes(set: &HashedSet, paths: &[PathBuf]) -> Result<()> {
+    let mut second_hashes = FxHashMap::<u128, u128>::default();
+    let mut collided = false;
+    for operand in Remaining::from(paths.to_vec()) {
+        operand?.for_byte_line(|line| {
+            let hash = xxh3_128(line);
+            if set.contains_key(&hash) {
+                let second = xxh3_128_with_seed(line, VERIFY_SEED);
+                collided |= *second_hashes.entry(hash).or_insert(second) != second;
+            }
+        })?;
+        if collided {
+            bail!("Two different lines have the same hash; try again without --hash-only")
+        }
+    }
+    Ok(())
+}
+
+#[allow(clippy::pedantic)]
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn belongs_in_sifts_as_the_set_operations_do() {
+        let counts = |lines, files| Counts { lines, files, ..Counts::new(0) };
+        assert!(belongs_in(Diff, counts(3, 1), 3));
+        assert!(!belongs_in(Diff, counts(3, 2), 3));
+        assert!(belongs_in(Intersect, counts(3, 3), 3));
+        assert!(!belongs_in(Single, counts(2, 1), 3));
+        assert!(belongs_in(Multiple, counts(2, 1), 3));
+        assert!(!belongs_in(MultipleByFile, counts(2, 1), 3));
+    }
+}
@@ -18,7 +18,7 @@ struct Entry<'a> {
     caption: &'a str,
 }
 
-fn name(style: &StyleSheet) -> StyledStr {
+fn name(style: &StyleSheet) -> StyledStr<'_> {
     style.app_name("zet")
 }
 
@@ -36,7 +36,9 @@ pub(crate) fn print() {
     for help_item in help {
         match help_item {
             HelpItem::Paragraph(text) => {
-                wrap(text, &C.wrap_options).iter().for_each(|line| println!("{line}"))
+                for line in wrap(text, &C.wrap_options) {
+                    println!("{line}")
+                }
             }
             HelpItem::Usage(args) => {
                 println!("{}{}{}", style.title("Usage: "), name(style), args)
@@ -45,7 +47,7 @@ pub(crate) fn print() {
                 println!("{}", style.title(s.title));
                 s.print_entries();
             }
-        };
+        }
     }
 }
 
@@ -108,8 +110,7 @@ impl<'a> Section<'a> {
         }
     }
     fn next_line_help_indent(&self) -> &'a str {
-        let max_indent =
-            self.entries.iter().map(|e| e.item.indented_by()).fold(0, std::cmp::Ord::max);
+        let max_indent = self.entries.iter().map(|e| e.item.indented_by()).fold(0, Ord::max);
         let indent_len = (max_indent + 4).min(BLANKS.len());
         &BLANKS[..indent_len]
     }
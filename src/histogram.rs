@@ -0,0 +1,131 @@
+//! Provides the `Histogram` type used by the `--histogram` option. Rather than
+//! the result lines themselves, a histogram reports how many of them have each
+//! line count (or file count): how many distinct lines occur once, how many
+//! twice, and so on.
+use anyhow::Result;
+#[cfg(feature = "cli")]
+use clap::ValueEnum;
+use std::collections::BTreeMap;
+use std::io;
+
+/// Which count to report the distribution of
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
+pub enum HistogramBy {
+    /// The number of times each line occurs in the input
+    Lines,
+    /// The number of files each line occurs in
+    Files,
+}
+
+/// How to group counts into buckets
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
+pub enum Buckets {
+    /// One bucket for each count that occurs (the default)
+    #[default]
+    Linear,
+    /// Buckets for 1, 2, 3–10, 11–100, 101–1000, and so on
+    Log,
+}
+
+/// The `--histogram` report requested
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Histogram {
+    /// Which count to report on
+    pub by: HistogramBy,
+    /// How to group the counts
+    pub buckets: Buckets,
+    /// Should we draw a bar for each bucket? (We do when writing to a
+    /// terminal.)
+    pub bars: bool,
+}
+
+/// The widest bar we'll draw
+const BAR_WIDTH: u64 = 50;
+
+impl Histogram {
+    /// Write one line for each bucket: the bucket's range of counts, a tab,
+    /// and the number of lines whose count falls in that range — followed,
+    /// if `bars` is set, by a tab and a bar proportional to that number.
+    /// `Linear` buckets are written only for counts that occur; `Log` buckets
+    /// are written from the first up to the last one that has any lines, so
+    /// that gaps in the distribution are visible.
+    pub(crate) fn write(
+        self,
+        counts: impl Iterator<Item = u32>,
+        line_terminator: &[u8],
+        mut out: impl io::Write,
+    ) -> Result<()> {
+        let mut tally = BTreeMap::<(u64, u64), u64>::new();
+        for count in counts {
+            *tally.entry(self.bucket(count)).or_default() += 1;
+        }
+        if self.buckets == Buckets::Log {
+            if let Some(&(_, high)) = tally.keys().next_back() {
+                let mut low = 1;
+                while low <= high {
+                    let bucket = self.bucket(u32::try_from(low).unwrap_or(u32::MAX));
+                    tally.entry(bucket).or_default();
+                    low = bucket.1 + 1;
+                }
+            }
+        }
+        let most = tally.values().copied().max().unwrap_or(0);
+        for (&(low, high), &lines) in &tally {
+            if low == high {
+                write!(out, "{low}\t{lines}")?
+            } else {
+                write!(out, "{low}-{high}\t{lines}")?
+            }
+            if self.bars {
+                let width = (lines * BAR_WIDTH).div_ceil(most.max(1));
+                write!(out, "\t{}", "#".repeat(usize::try_from(width)?))?
+            }
+            out.write_all(line_terminator)?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+
+    /// The range of counts (inclusive) in the bucket for `count`
+    fn bucket(self, count: u32) -> (u64, u64) {
+        let count = u64::from(count);
+        match self.buckets {
+            Buckets::Linear => (count, count),
+            Buckets::Log if count <= 2 => (count, count),
+            Buckets::Log => {
+                let high = 10_u64.pow((count - 1).ilog10() + 1);
+                (3.max(high / 10 + 1), high)
+            }
+        }
+    }
+}
+
+#[allow(clippy::pedantic)]
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn written(buckets: Buckets, bars: bool, counts: &[u32]) -> String {
+        let histogram = Histogram { by: HistogramBy::Lines, buckets, bars };
+        let mut out = Vec::new();
+        histogram.write(counts.iter().copied(), b"\n", &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn linear_buckets_are_written_for_the_counts_that_occur() {
+        assert_eq!(written(Buckets::Linear, false, &[1, 5, 1, 2]), "1\t2\n2\t1\n5\t1\n");
+        let (full, half) = ("#".repeat(50), "#".repeat(25));
+        let expected = format!("1\t2\t{full}\n3\t1\t{half}\n");
+        assert_eq!(written(Buckets::Linear, true, &[1, 3, 1]), expected);
+    }
+
+    #[test]
+    fn log_buckets_are_written_up_to_the_largest() {
+        let expected = "1\t1\n2\t0\n3-10\t2\n11-100\t0\n101-1000\t1\n";
+        assert_eq!(written(Buckets::Log, false, &[10, 1, 3, 1000]), expected);
+        assert_eq!(written(Buckets::Log, false, &[]), "");
+    }
+}
@@ -0,0 +1,261 @@
+//! Provides `SetIndex`, a `.zidx` file written by `zet index build`. An index
+//! holds the 128-bit hash (as used by `--hash-only`) of each distinct line of
+//! a file, sorted, so that `diff` and `intersect` can test their lines against
+//! a large file they use again and again without reading and hashing it each
+//! time. The index is memory-mapped rather than read: a lookup touches only
+//! the pages it needs.
+//!
+//! An index can't tell us its lines, only whether it has a given line, so it
+//! can only be an operand whose lines are used for membership tests: any
+//! operand of `intersect` (so long as some operand is a text file), or a
+//! later operand of `diff`.
+//!
+//! The format (all integers little-endian):
+//!
+//! | Offset | Size | Contents |
+//! |-------:|-----:|----------|
+//! | 0 | 8 | The magic number `ZETINDEX` |
+//! | 8 | 4 | The format version, `FORMAT_VERSION` |
+//! | 12 | 4 | `bits`: hashes are grouped into `2^bits` buckets by their top `bits` bits |
+//! | 16 | 8 | `count`: the number of hashes |
+//! | 24 | 8 | The `xxh3_64` checksum of the rest of the file (bytes 0–23 and 32 on) |
+//! | 32 | 8 | The source file's length when the index was built |
+//! | 40 | 8 | The source file's modification time: seconds since the Unix epoch |
+//! | 48 | 4 | … and nanoseconds |
+//! | 52 | 4 | `n`: the length of the source file's path |
+//! | 56 | `n` | The source file's canonical path, as UTF-8, then padding to a multiple of 8 bytes |
+//! | | `8·(2^bits+1)` | For each bucket, the position of its first hash; then `count` |
+//! | | `16·count` | The hashes, in ascending order |
+//!
+//! We reject an index whose magic number, version, length, or checksum is
+//! wrong, and one whose source file still exists but has changed since the
+//! index was built.
+use crate::operands::reader_for;
+use crate::set::LaterOperand;
+use anyhow::{bail, Context, Result};
+use memmap2::Mmap;
+use std::cmp::Ordering;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use xxhash_rust::xxh3::{xxh3_128, Xxh3};
+
+const MAGIC: &[u8; 8] = b"ZETINDEX";
+const FORMAT_VERSION: u32 = 1;
+const HEADER_LEN: usize = 56;
+const CHECKSUM: std::ops::Range<usize> = 24..32;
+const HASH_LEN: usize = 16;
+
+/// The file extension that marks an operand as an index
+pub const EXTENSION: &str = "zidx";
+
+/// Is the operand at `path` an index, by its extension?
+#[must_use]
+pub fn is_index(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension == EXTENSION)
+}
+
+/// The number of bits of each hash used to choose its bucket: enough for an
+/// average of 16 to 32 hashes per bucket, up to a million buckets.
+fn bucket_bits(count: u64) -> u32 {
+    (count.max(1).ilog2().saturating_sub(4)).min(20)
+}
+
+/// The bucket of `hash`, given `bits`
+fn bucket(hash: u128, bits: u32) -> usize {
+    if bits == 0 {
+        0
+    } else {
+        usize::try_from(hash >> (128 - bits)).expect("bits is at most 20")
+    }
+}
+
+/// Write an index of the lines of `source` to `out`. Lines are read as they
+/// would be for any operand: UTF-16 is translated, and a Byte Order Mark and
+/// line terminators are removed.
+///
+/// # Errors
+///
+/// Returns an error if `source` can't be read, or `out` can't be written.
+pub fn build(source: &Path, mut out: impl Write) -> Result<()> {
+    if is_index(source) {
+        bail!("{} is already an index", source.display())
+    }
+    let metadata =
+        fs::metadata(source).with_context(|| format!("Can't read file: {}", source.display()))?;
+    let mut hashes = Vec::new();
+    reader_for(source)?.for_byte_line(|line| hashes.push(xxh3_128(line)))?;
+    hashes.sort_unstable();
+    hashes.dedup();
+
+    let count = hashes.len() as u64;
+    let bits = bucket_bits(count);
+    let mut starts = vec![0_u64; (1 << bits) + 1];
+    for &hash in &hashes {
+        starts[bucket(hash, bits) + 1] += 1;
+    }
+    for b in 1..starts.len() {
+        starts[b] += starts[b - 1];
+    }
+
+    let modified = metadata.modified().ok().and_then(|time| time.duration_since(UNIX_EPOCH).ok());
+    let modified = modified.unwrap_or_default();
+    let path = fs::canonicalize(source).unwrap_or_else(|_| source.to_path_buf());
+    let path = path.to_string_lossy();
+    let mut header = Vec::with_capacity(HEADER_LEN + path.len() + 8);
+    header.extend_from_slice(MAGIC);
+    header.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    header.extend_from_slice(&bits.to_le_bytes());
+    header.extend_from_slice(&count.to_le_bytes());
+    header.extend_from_slice(&[0; 8]); // The checksum, filled in below
+    header.extend_from_slice(&metadata.len().to_le_bytes());
+    header.extend_from_slice(&modified.as_secs().to_le_bytes());
+    header.extend_from_slice(&modified.subsec_nanos().to_le_bytes());
+    header.extend_from_slice(&u32::try_from(path.len())?.to_le_bytes());
+    header.extend_from_slice(path.as_bytes());
+    header.resize(header.len().next_multiple_of(8), 0);
+
+    // Write the body twice, once to the checksum and once to `out`
+    let body = |write: &mut dyn FnMut(&[u8])| {
+        for start in &starts {
+            write(&start.to_le_bytes());
+        }
+        for hash in &hashes {
+            write(&hash.to_le_bytes());
+        }
+    };
+    let mut checksum = Xxh3::new();
+    checksum.update(&header[..CHECKSUM.start]);
+    checksum.update(&header[CHECKSUM.end..]);
+    body(&mut |bytes| checksum.update(bytes));
+    header[CHECKSUM].copy_from_slice(&checksum.digest().to_le_bytes());
+
+    out.write_all(&header)?;
+    let mut written = Ok(());
+    body(&mut |bytes| {
+        if written.is_ok() {
+            written = out.write_all(bytes);
+        }
+    });
+    written?;
+    out.flush()?;
+    Ok(())
+}
+
+/// A memory-mapped index, checked when opened
+pub struct SetIndex {
+    map: Mmap,
+    bits: u32,
+    /// Where the bucket starts begin
+    starts: usize,
+    /// Where the hashes begin
+    hashes: usize,
+}
+
+impl SetIndex {
+    /// Map and check the index at `path`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `path` can't be read, isn't an index this version
+    /// of `zet` can read, is corrupt, or is stale.
+    pub fn open(path: &Path) -> Result<Self> {
+        let name = path.display();
+        let file = File::open(path).with_context(|| format!("Can't open file: {name}"))?;
+        // SAFETY: the mapping is read-only, and we check its checksum, so an
+        // index changed since it was written would be rejected (unless it
+        // changes while we're using it).
+        let map =
+            unsafe { Mmap::map(&file) }.with_context(|| format!("Can't read file: {name}"))?;
+        let rebuild = "rebuild it with `zet index build`";
+        if map.len() < HEADER_LEN || &map[..8] != MAGIC {
+            bail!("{name} isn't a zet index")
+        }
+        let version = u32_at(&map, 8);
+        if version != FORMAT_VERSION {
+            bail!("{name} is in index format {version}, but this zet reads format {FORMAT_VERSION}; {rebuild}")
+        }
+        let corrupt = |why| anyhow::anyhow!("{name} is corrupt ({why}); {rebuild}");
+        let (bits, count) = (u32_at(&map, 12), u64_at(&map, 16));
+        if bits > 20 {
+            return Err(corrupt("it has too many buckets"));
+        }
+        let path_len = u32_at(&map, 52) as usize;
+        let starts = (HEADER_LEN + path_len).next_multiple_of(8);
+        let hashes = starts + 8 * ((1 << bits) + 1);
+        let expected_len = (hashes as u64).saturating_add(count.saturating_mul(HASH_LEN as u64));
+        if map.len() as u64 != expected_len {
+            return Err(corrupt("its length is wrong"));
+        }
+        let mut checksum = Xxh3::new();
+        checksum.update(&map[..CHECKSUM.start]);
+        checksum.update(&map[CHECKSUM.end..]);
+        if checksum.digest() != u64_at(&map, CHECKSUM.start) {
+            return Err(corrupt("its checksum doesn't match"));
+        }
+
+        let source = String::from_utf8_lossy(&map[HEADER_LEN..HEADER_LEN + path_len]);
+        if let Ok(metadata) = fs::metadata(&*source) {
+            let modified = Duration::new(u64_at(&map, 40), u32_at(&map, 48));
+            let built_from = UNIX_EPOCH + modified;
+            let now_modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            if metadata.len() != u64_at(&map, 32) || now_modified != built_from {
+                bail!("{name} is stale: {source} has changed since the index was built; {rebuild}")
+            }
+        }
+        Ok(SetIndex { map, bits, starts, hashes })
+    }
+
+    /// Does the index hold `line`?
+    #[must_use]
+    pub fn contains(&self, line: &[u8]) -> bool {
+        let hash = xxh3_128(line);
+        let start = self.starts + 8 * bucket(hash, self.bits);
+        // `open` checked that the hashes fit in the mapping, so their
+        // positions fit in a `usize`
+        #[allow(clippy::cast_possible_truncation)]
+        let (mut low, mut high) =
+            (u64_at(&self.map, start) as usize, u64_at(&self.map, start + 8) as usize);
+        while low < high {
+            let middle = low + (high - low) / 2;
+            match u128_at(&self.map, self.hashes + HASH_LEN * middle).cmp(&hash) {
+                Ordering::Less => low = middle + 1,
+                Ordering::Greater => high = middle,
+                Ordering::Equal => return true,
+            }
+        }
+        false
+    }
+}
+
+/// The little-endian `u32` at `offset` in `bytes`
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().expect("4 bytes"))
+}
+
+/// The little-endian `u64` at `offset` in `bytes`
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().expect("8 bytes"))
+}
+
+/// The little-endian `u128` at `offset` in `bytes`
+fn u128_at(bytes: &[u8], offset: usize) -> u128 {
+    u128::from_le_bytes(bytes[offset..offset + HASH_LEN].try_into().expect("16 bytes"))
+}
+
+#[allow(clippy::pedantic)]
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn buckets_cover_the_hashes_in_order() {
+        assert_eq!(bucket_bits(0), 0);
+        assert_eq!(bucket_bits(1000), 5);
+        assert_eq!(bucket_bits(u64::MAX), 20);
+        assert_eq!(bucket(u128::MAX, 0), 0);
+        assert_eq!(bucket(u128::MAX, 20), (1 << 20) - 1);
+        assert!(bucket(1 << 100, 20) < bucket(1 << 120, 20));
+    }
+}
@@ -31,12 +31,13 @@
 //!   first line of the first input file.
 //! * We process all input files before doing any output. (This is not
 //!   absolutely necessary for the `Union` operation — see the
-//!   [huniq](https://crates.io/crates/huniq) command. But it is for all other
-//!   Zet operations.)
+//!   [huniq](https://crates.io/crates/huniq) command, and the `stream` module,
+//!   used for `--stream`. But it is for all other Zet operations.)
 //!
 //! The `set` module provides the `ZetSet` structure. The `ZetSet::new` function
-//! takes a `&[u8]` slice and a bookkeeping item used by the calling operation.
-//! The call `ZetSet::new(slice, item)` returns an initialized `ZetSet` with:
+//! takes a `&[u8]` slice, a bookkeeping item used by the calling operation, and
+//! a memory `Budget`.
+//! The call `ZetSet::new(slice, item, budget)` returns an initialized `ZetSet` with:
 //! * An `IndexMap` whose keys (lines) are borrowed from `slice` and initial
 //!   bookkeeping values equal to `item`, and possibly updated if seen multiple
 //!   times in the slice.
@@ -45,17 +46,24 @@
 //!   line of `slice`.
 //!
 //! For a `ZetSet` `z`,
-//! * `z.insert_or_update(operand, item)` uses `IndexMap`'s `entry` method to
-//!   insert `item` as the value for lines in `operand` that were not already
-//!   present in `z`, or to call `v.update_with(item)` on the bookkeeping item
-//!   of lines that were present. Inserted lines are allocated, not borrowed, so
-//!   `operand` need not outlive `z`.
+//! * `z.insert_or_update(operand, arena, budget, item)` inserts `item` as the value
+//!   for lines in `operand` that were not already present in `z`, or calls
+//!   `v.update_with(item)` on the bookkeeping item of lines that were present.
+//!   Inserted lines are copied into `arena`, which outlives `z`, rather than
+//!   borrowed from `operand`, so `operand` need not outlive `z`.
+//!   The memory `z` uses is charged to `budget`, as with `ZetSet::new`, so
+//!   that `--max-memory` can stop us before we run out.
 //! * `z.update_if_present(operand, item)` calls `v.update_with(file_number)`
 //!   on the bookkeeping item of lines in operand that are present in `z`,
 //!   ignoring lines that are not already present.
 //! * Finally, `z.retain(keep)` retains lines for which
 //!   `keep(item.retention_value())` is true of the line's bookkeeping item.
 //!
+//! Bookkeeping values are `set::Aggregate`s, a public trait, so library users
+//! can keep state of their own for each line with a `builder::Aggregation`.
+//! Library users should start with the `builder` module, which wraps all of
+//! this in a stable interface.
+//!
 #![deny(
     warnings,
     clippy::all,
@@ -78,9 +86,26 @@
 )]
 #![cfg_attr(debug_assertions, allow(dead_code, unused_imports, unused_variables))]
 
+#[cfg(feature = "cli")]
 pub mod args;
+pub mod atomic;
+pub mod budget;
+pub mod builder;
+pub mod hashed;
+#[cfg(feature = "cli")]
 pub mod help;
+pub mod histogram;
+pub mod index;
 pub mod operands;
 pub mod operations;
+pub mod order;
+pub mod output;
+pub(crate) mod parallel;
+pub mod plan;
 pub mod set;
+pub mod sorted;
+pub mod spill;
+pub mod stream;
 pub mod styles;
+pub mod template;
+pub mod watch;
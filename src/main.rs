@@ -1,14 +1,80 @@
 use anyhow::{bail, Result};
 use is_terminal::IsTerminal;
-use std::io;
-use zet::args::OpName;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use zet::args::Args;
+use zet::atomic::AtomicFile;
+use zet::budget::{format_size, peak_memory, OverBudget};
+use zet::hashed::calculate_hashed;
+use zet::index::is_index;
 use zet::operands::first_and_rest;
-use zet::operations::calculate;
+use zet::operations::{calculate, calculate_many, Base, CountMode, Operation, Reading};
+use zet::output::OutputFormat;
+use zet::plan::plan;
+use zet::sorted::calculate_sorted;
+use zet::spill::calculate_spilled;
+use zet::stream::stream_union;
+use zet::styles::{self, StyleSheet};
+use zet::watch::{check_watchable, Cache, Watcher};
 
 fn main() -> Result<()> {
     let args = zet::args::parsed();
+    let verbose = args.verbose;
+    let result = run(args);
+    if verbose {
+        match peak_memory() {
+            Some(peak) => eprintln!("zet: peak memory use was {}", format_size(peak)),
+            None => eprintln!("zet: peak memory use isn't available on this system"),
+        }
+    }
+    result
+}
+
+fn run(mut args: Args) -> Result<()> {
+    if args.build_index {
+        let mut file = destination(args.output_file.as_deref())?;
+        zet::index::build(&args.paths[0], writer(&mut file))?;
+        return commit([file]);
+    }
+    if args.watch {
+        return watch(args);
+    }
 
-    let paths = first_and_rest(&args.paths).or_else(|| first_and_rest(&["-".into()]));
+    let reading = &args.reading;
+    if reading.hash_only || reading.memory_limit.is_some() || reading.sorted || reading.stream {
+        let option = if reading.hash_only {
+            "--hash-only"
+        } else if reading.memory_limit.is_some() {
+            "--memory-limit"
+        } else if reading.sorted {
+            "--sorted"
+        } else {
+            "--stream"
+        };
+        check_no_index(&args.paths, option)?;
+        let stdin = ["-".into()];
+        let paths = if args.paths.is_empty() { &stdin[..] } else { &args.paths[..] };
+        let mut file = destination(args.output_file.as_deref())?;
+        let (op, log_type, output) = (args.op, args.log_type, &args.output);
+        if let Some(limit) = reading.memory_limit {
+            calculate_spilled(op, log_type, output, paths, limit, writer(&mut file))?;
+        } else if reading.stream {
+            // Flush each line unless it's going to a file, where no one is
+            // waiting to read it
+            let flush_each_line = file.is_none() && !stdout_is_file();
+            stream_union(op, log_type, output, paths, flush_each_line, writer(&mut file))?;
+        } else if reading.sorted {
+            calculate_sorted(op, log_type, output, paths, writer(&mut file))?;
+        } else {
+            let verify = reading.verify_hashes;
+            calculate_hashed(op, log_type, output, paths, verify, writer(&mut file))?;
+        }
+        return commit([file]);
+    }
+
+    let (paths, base) = planned(&args)?;
+    args.reading.base = base;
+    let paths = first_and_rest(&paths).or_else(|| first_and_rest(&["-".into()]));
     let (first_operand, rest) = match paths {
         None => {
             bail!("This can't happen: with no file arguments, zet should read from standard input")
@@ -16,16 +82,43 @@ fn main() -> Result<()> {
         Some((first, others)) => (first?, others),
     };
 
+    if !args.emits.is_empty() {
+        check_no_index(&args.paths, "--emit")?;
+        // Write the command's result to stdout (or the --output file), and
+        // each `--emit` result to its own file, from a single pass over the
+        // input.
+        let mut files = vec![destination(args.output_file.as_deref())?];
+        for (_, path) in &args.emits {
+            files.push(destination(Some(path))?);
+        }
+        let ops = std::iter::once(args.op).chain(args.emits.iter().map(|(op, _)| *op));
+        let to_stdout = files.iter().all(Option::is_none);
+        args.output.style = result_style(&args, to_stdout);
+        let outs = files.iter_mut().map(|file| first_operand.guard(writer(file)));
+        let operations = ops.zip(outs).collect();
+        let (log_type, output, reading) = (args.log_type, &args.output, &args.reading);
+        let result = calculate_many(operations, log_type, output, reading, &first_operand, rest);
+        if let Some(over) = over_budget(&result) {
+            bail!(over_budget_message(&args, over))
+        }
+        result?;
+        first_operand.check_unchanged()?;
+        return commit(files);
+    }
+
     let mut op = args.op;
+    let mut nothing_to_do = false;
     if rest.len() == 0 {
-        use OpName::*;
+        use Operation::*;
         match op {
             // For a single operand, Union is slightly more efficient, and its
             // result is identical to Intersect, Diff, and SingleByFile
             Union | Intersect | Diff | SingleByFile => op = Union,
 
-            // No line can occur in multiple files if there is only one file
-            MultipleByFile => return Ok(()),
+            // No line can occur in multiple files if there is only one file.
+            // (But a JSON array must still be written, even if it's empty.)
+            MultipleByFile if args.output.format == OutputFormat::Text => nothing_to_do = true,
+            MultipleByFile => {}
 
             // Even for a single operand, the results of Single and Multiple
             // differ from that of Union
@@ -33,12 +126,192 @@ fn main() -> Result<()> {
         }
     }
 
-    let first = first_operand.as_slice();
-    //panic!("\n\n\n\n\n\n###########################{op:?}                {:?}\n", args.log_type);
-    if io::stdout().is_terminal() {
-        calculate(op, args.log_type, first, rest, io::stdout().lock())?;
-    } else {
-        calculate(op, args.log_type, first, rest, io::BufWriter::new(io::stdout().lock()))?;
+    let mut file = destination(args.output_file.as_deref())?;
+    args.output.style = result_style(&args, file.is_none());
+    if let Some(histogram) = &mut args.output.histogram {
+        histogram.bars = file.is_none() && io::stdout().is_terminal();
+    }
+    if !nothing_to_do {
+        let first = first_operand.as_slice();
+        let (log_type, output, reading) = (args.log_type, &args.output, &args.reading);
+        let out = first_operand.guard(writer(&mut file));
+        let result = calculate(op, log_type, output, reading, first, rest, out);
+        if let Some(over) = over_budget(&result) {
+            // Nothing has been written yet, so if we can read the input
+            // again, we can start over, spilling it to temporary files — as
+            // long as spilling would write the same result
+            let message = over_budget_message(&args, over);
+            // (An index's lines can't be read, so can't be spilled.)
+            let rereadable = !args.paths.is_empty()
+                && args.paths.iter().all(|path| path.is_file() && !is_index(path));
+            if output.needs_tally() || output.reorders() || !rereadable {
+                bail!(message)
+            }
+            let limit = over.limit / 2;
+            if args.verbose {
+                eprintln!("zet: {message}; using --memory-limit {} instead", format_size(limit));
+            }
+            let paths = &args.paths;
+            calculate_spilled(args.op, log_type, output, paths, limit, writer(&mut file))?;
+            return commit([file]);
+        }
+        result?;
+        first_operand.check_unchanged()?;
+    }
+    commit([file])
+}
+
+/// Calculate the result, and calculate it again each time an operand changes,
+/// until we're interrupted. On a terminal, each result replaces the last;
+/// an `--output` file is rewritten. Errors are reported, and we keep
+/// watching, since the next change may fix them.
+fn watch(mut args: Args) -> Result<()> {
+    check_watchable(&args.paths)?;
+    let mut watcher = Watcher::new(&args.paths)?;
+    let mut cache = Cache::default();
+    let to_stdout = destination(args.output_file.as_deref())?.is_none();
+    args.output.style = result_style(&args, to_stdout);
+    let clear_screen = to_stdout && io::stdout().is_terminal();
+    if let Some(histogram) = &mut args.output.histogram {
+        histogram.bars = clear_screen;
+    }
+    let mut changed = Vec::new();
+    loop {
+        if let Err(error) = recalculate(&args, &mut cache, &changed, clear_screen) {
+            eprintln!("zet: {error:#}");
+        }
+        changed = watcher.wait()?;
+    }
+}
+
+/// Calculate the result for `--watch`, reading only the operands that have
+/// changed since `cache` last read them
+fn recalculate(args: &Args, cache: &mut Cache, changed: &[PathBuf], clear: bool) -> Result<()> {
+    let read = cache.refresh(&args.paths, changed)?;
+    if args.verbose {
+        eprintln!("zet: read {read} of {} operands", args.paths.len());
+    }
+    let (paths, base) = planned(args)?;
+    let reading = Reading { base, ..args.reading.clone() };
+    let first = cache.first_operand(&paths[0])?;
+    let rest = paths[1..].iter().map(|path| cache.later_operand(path));
+    let mut file = destination(args.output_file.as_deref())?;
+    if clear {
+        // Clear the screen, and move the cursor to the top left corner
+        print!("\x1b[2J\x1b[H");
+    }
+    let (op, log_type, output) = (args.op, args.log_type, &args.output);
+    calculate(op, log_type, output, &reading, first, rest, writer(&mut file))?;
+    commit([file])
+}
+
+/// The operands in the order to read them, and the `Base` operand moved to
+/// the front, if any: the smallest operand for `intersect`, or the `--base`
+/// operand. (`--threads` and `--emit` read the operands in command-line
+/// order.)
+fn planned(args: &Args) -> Result<(Vec<PathBuf>, Option<Base>)> {
+    let mut paths = args.paths.clone();
+    if !args.emits.is_empty() || args.reading.threads != 1 {
+        return Ok((paths, None));
+    }
+    let base = plan(args.op, &paths, args.base)?;
+    if let Some(base) = base {
+        paths[..=base.position].rotate_right(1);
+    }
+    Ok((paths, base))
+}
+
+/// Fail if any of `paths` is an index: only `calculate`, which can look lines
+/// up in an index rather than read them, can use one, and `option` chooses
+/// another way to read the operands.
+fn check_no_index(paths: &[PathBuf], option: &str) -> Result<()> {
+    if let Some(path) = paths.iter().find(|path| is_index(path)) {
+        bail!("{}: zet can't read the lines of an index, as {option} would need to", path.display())
+    }
+    Ok(())
+}
+
+/// The `OverBudget` error in `result`, if that's what it holds
+fn over_budget(result: &Result<()>) -> Option<OverBudget> {
+    result.as_ref().err().and_then(|error| error.downcast_ref::<OverBudget>()).copied()
+}
+
+/// Explain an `OverBudget` error, naming the operand we were reading and
+/// estimating how much memory the whole input would need. For `diff` and
+/// `intersect`, only the operand read first adds lines to the result, so
+/// that operand is the whole input that counts; standard input has no size
+/// we can know.
+fn over_budget_message(args: &Args, over: OverBudget) -> String {
+    let name =
+        args.output.operand_names.get(over.operand as usize).map_or("<stdin>", String::as_str);
+    let size = |path: &PathBuf| path.metadata().ok().filter(|m| m.is_file()).map(|m| m.len());
+    let input_size = match args.op {
+        Operation::Diff | Operation::Intersect => {
+            args.paths.get(over.operand as usize).and_then(size)
+        }
+        _ if args.paths.is_empty() => None,
+        _ => args.paths.iter().map(size).sum(),
+    };
+    let needed = match input_size {
+        Some(input_size) => format!("about {}", format_size(over.estimate(input_size))),
+        None => format!("more than {}", format_size(over.used())),
     };
+    format!(
+        "{name}: zet would need {needed} of memory, more than --max-memory {} allows",
+        format_size(over.limit)
+    )
+}
+
+/// Where to write a result: an `AtomicFile` for `path`, or `None` for standard
+/// output (when there's no `path`, or it's `-`)
+fn destination(path: Option<&Path>) -> Result<Option<AtomicFile>> {
+    match path {
+        Some(path) if path.as_os_str() != "-" => Ok(Some(AtomicFile::create(path)?)),
+        _ => Ok(None),
+    }
+}
+
+/// Is standard output redirected to a regular file (rather than a terminal or
+/// pipe)?
+fn stdout_is_file() -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::fd::AsFd;
+        let stdout = io::stdout().as_fd().try_clone_to_owned().map(std::fs::File::from);
+        stdout.and_then(|file| file.metadata()).is_ok_and(|metadata| metadata.is_file())
+    }
+    #[cfg(not(unix))]
+    {
+        false
+    }
+}
+
+/// The style sheet for the result. Only counts and provenance labels are
+/// colored, so when there are none we leave the result plain, and `calculate`
+/// can use its faster ways of writing it.
+fn result_style(args: &Args, to_stdout: bool) -> StyleSheet {
+    if matches!(args.log_type, CountMode::None) && !args.output.provenance {
+        StyleSheet::default()
+    } else {
+        styles::result_style(to_stdout)
+    }
+}
+
+/// A writer for a `destination`; standard output is buffered unless it's a
+/// terminal
+fn writer(file: &mut Option<AtomicFile>) -> Box<dyn Write + '_> {
+    match file {
+        Some(file) => Box::new(file),
+        None if io::stdout().is_terminal() => Box::new(io::stdout()),
+        None => Box::new(io::BufWriter::new(io::stdout())),
+    }
+}
+
+/// Replace each output file's target with its new contents. We only get here
+/// if every result was written successfully.
+fn commit(files: impl IntoIterator<Item = Option<AtomicFile>>) -> Result<()> {
+    for file in files.into_iter().flatten() {
+        file.commit()?;
+    }
     Ok(())
 }
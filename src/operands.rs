@@ -4,30 +4,33 @@
 //! remaining operands has the unfortunate result of requiring different code
 //! paths for translating UTF16 files into UTF8. That currently seems worth the
 //! cost.
-use crate::set::LaterOperand;
-use anyhow::{Context, Result};
+use crate::index::{is_index, SetIndex};
+use crate::set::{output_info, LaterOperand};
+use anyhow::{bail, Context, Result};
 use bstr::io::BufReadExt;
 use encoding_rs_io::{DecodeReaderBytes, DecodeReaderBytesBuilder};
+use memmap2::Mmap;
 use std::{
     fs,
     fs::File,
-    io::{self, Read},
-    ops::FnMut,
+    io::{self, Read, Write},
+    ops::{ControlFlow, Deref, FnMut},
     path::{Path, PathBuf},
+    time::SystemTime,
 };
 
 /// The Unix convention: if a file argument is `-`, use `stdin`.
-fn use_stdin(path: &Path) -> bool {
+pub(crate) fn use_stdin(path: &Path) -> bool {
     path.to_string_lossy() == "-"
 }
-/// Return the contents of the first file named in `files` as a `Vec<u8>`, and
-/// an `ExactSizeIterator` over the subsequent arguments.
+/// Return the contents of the first file named in `files` as a
+/// `FirstOperand`, and an `ExactSizeIterator` over the subsequent arguments.
 #[must_use]
-pub fn first_and_rest(files: &[PathBuf]) -> Option<(Result<Vec<u8>>, Remaining)> {
-    fn all_of_stdin() -> Result<Vec<u8>> {
+pub fn first_and_rest(files: &[PathBuf]) -> Option<(Result<FirstOperand>, Remaining)> {
+    fn all_of_stdin() -> Result<FirstOperand> {
         let mut buffer = Vec::new();
         io::stdin().read_to_end(&mut buffer).context("Can't read file: <stdin>")?;
-        Ok(decode_if_utf16(buffer))
+        Ok(FirstOperand::Read(decode_if_utf16(buffer)))
     }
 
     match files {
@@ -36,9 +39,7 @@ pub fn first_and_rest(files: &[PathBuf]) -> Option<(Result<Vec<u8>>, Remaining)>
             let first_operand = if use_stdin(first) {
                 all_of_stdin()
             } else {
-                fs::read(first)
-                    .with_context(|| format!("Can't read file: {}", first.display()))
-                    .map(decode_if_utf16)
+                FirstOperand::open(first, MMAP_THRESHOLD)
             };
             let rest = rest.to_vec();
             Some((first_operand, Remaining::from(rest)))
@@ -46,23 +47,177 @@ pub fn first_and_rest(files: &[PathBuf]) -> Option<(Result<Vec<u8>>, Remaining)>
     }
 }
 
+/// Files smaller than this are read rather than mapped: for them, reading is
+/// cheaper than setting up a mapping.
+const MMAP_THRESHOLD: u64 = 1024 * 1024;
+
+/// The contents of the first operand. A regular file of at least
+/// `MMAP_THRESHOLD` bytes is memory-mapped, so that `ZetSet::new` can borrow
+/// its lines straight from the page cache, rather than from a copy. Standard
+/// input, pipes, small files, and UTF-16 files (which must be translated to
+/// UTF-8) are read into memory.
+///
+/// A mapped file that changes while we're using it could change our result
+/// out from under us, so callers should write the result through `guard`, and
+/// call `check_unchanged` once they're done with the contents. (A file
+/// truncated while mapped may instead crash the process with `SIGBUS` —
+/// there's no way to make that safe, short of not mapping the file.)
+pub enum FirstOperand {
+    Read(Vec<u8>),
+    Mapped(Mapping),
+}
+
+/// A memory-mapped file, with what we need to tell whether it has changed
+pub struct Mapping {
+    map: Mmap,
+    path: PathBuf,
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+impl FirstOperand {
+    /// Map the file at `path` if it's a regular file of at least `threshold`
+    /// bytes that doesn't need translation; otherwise read it.
+    fn open(path: &Path, threshold: u64) -> Result<Self> {
+        if is_index(path) {
+            bail!(
+                "{}: zet can't read the lines of an index, so it can't be the operand read first",
+                path.display()
+            )
+        }
+        let cant_read = || format!("Can't read file: {}", path.display());
+        let file = File::open(path).with_context(cant_read)?;
+        let metadata = file.metadata().with_context(cant_read)?;
+        if metadata.is_file() && metadata.len() >= threshold.max(1) {
+            // SAFETY: the mapping is read-only, and `check_unchanged` lets
+            // callers find out if the file changed while mapped.
+            let map = unsafe { Mmap::map(&file) }.with_context(cant_read)?;
+            if !is_utf16(&map) {
+                let len = metadata.len();
+                let modified = metadata.modified().ok();
+                return Ok(FirstOperand::Mapped(Mapping { map, path: path.into(), len, modified }));
+            }
+        }
+        let mut contents = Vec::new();
+        (&file).read_to_end(&mut contents).with_context(cant_read)?;
+        Ok(FirstOperand::Read(decode_if_utf16(contents)))
+    }
+
+    /// The contents of the operand
+    #[must_use]
+    pub fn as_slice(&self) -> &[u8] {
+        match self {
+            FirstOperand::Read(contents) => contents,
+            FirstOperand::Mapped(mapping) => &mapping.map,
+        }
+    }
+
+    /// Fail if the operand is a mapped file whose size or modification time
+    /// has changed since we mapped it — in which case lines we borrowed from
+    /// the mapping may have changed too.
+    pub fn check_unchanged(&self) -> Result<()> {
+        let FirstOperand::Mapped(mapping) = self else { return Ok(()) };
+        let unchanged = fs::metadata(&mapping.path).is_ok_and(|metadata| {
+            metadata.len() == mapping.len && metadata.modified().ok() == mapping.modified
+        });
+        if !unchanged {
+            bail!(
+                "{} changed while zet was reading it, so the result may be wrong",
+                mapping.path.display()
+            )
+        }
+        Ok(())
+    }
+
+    /// Wrap `out` in a `Guarded` writer for this operand. (`calculate` reads
+    /// all its input before writing anything, so by the time anything is
+    /// written, we know whether the file changed while we read it.)
+    pub fn guard<W: Write>(&self, out: W) -> Guarded<'_, W> {
+        Guarded { operand: self, out, checked: false }
+    }
+}
+
+/// A writer that calls `FirstOperand::check_unchanged` before anything is
+/// written to it, so that a result calculated from a file that changed while
+/// we read it isn't written at all. Returned by `FirstOperand::guard`.
+pub struct Guarded<'a, W: Write> {
+    operand: &'a FirstOperand,
+    out: W,
+    checked: bool,
+}
+impl<W: Write> Guarded<'_, W> {
+    fn check(&mut self) -> io::Result<()> {
+        if !self.checked {
+            self.operand.check_unchanged().map_err(|e| io::Error::other(format!("{e:#}")))?;
+            self.checked = true;
+        }
+        Ok(())
+    }
+}
+impl<W: Write> Write for Guarded<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.check()?;
+        self.out.write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.check()?;
+        self.out.flush()
+    }
+}
+
+impl Deref for FirstOperand {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+/// Return the `(bom, line_terminator)` pair that `ZetSet::new` would find for
+/// the file at `path`, looking only at the start of the file. For standard
+/// input, which we can't look at twice, we return the defaults: no byte order
+/// mark, and `\n`.
+pub(crate) fn output_info_for(path: &Path) -> Result<(&'static [u8], &'static [u8])> {
+    const PEEK: u64 = 64 * 1024;
+    if use_stdin(path) {
+        return Ok((b"", b"\n"));
+    }
+    let mut start = Vec::new();
+    File::open(path)
+        .and_then(|f| f.take(PEEK).read_to_end(&mut start))
+        .with_context(|| format!("Can't read file: {}", path.display()))?;
+    Ok(output_info(&decode_if_utf16(start)))
+}
+
 /// Decode UTF-16 to UTF-8 if we see a UTF-16 Byte Order Mark at the beginning of `candidate`.
 /// Otherwise return `candidate` unchanged
-fn decode_if_utf16(candidate: Vec<u8>) -> Vec<u8> {
+pub(crate) fn decode_if_utf16(candidate: Vec<u8>) -> Vec<u8> {
+    utf16_decoded(&candidate).unwrap_or(candidate)
+}
+
+/// The UTF-16 encoding whose Byte Order Mark `candidate` begins with, if any
+fn utf16_encoding(candidate: &[u8]) -> Option<&'static encoding_rs::Encoding> {
+    let (enc, _) = encoding_rs::Encoding::for_bom(candidate)?;
+    [encoding_rs::UTF_16LE, encoding_rs::UTF_16BE].contains(&enc).then_some(enc)
+}
+
+/// Does `candidate` begin with a UTF-16 Byte Order Mark? (Looks only at the
+/// first few bytes.)
+fn is_utf16(candidate: &[u8]) -> bool {
+    utf16_encoding(candidate).is_some()
+}
+
+/// If `candidate` begins with a UTF-16 Byte Order Mark, return it translated
+/// to UTF-8; otherwise return `None`.
+fn utf16_decoded(candidate: &[u8]) -> Option<Vec<u8>> {
     // Translate UTF16 to UTF8
     // Note: `decode_without_bom_handling` will change malformed sequences to the
     // Unicode REPLACEMENT CHARACTER. Should we report an error instead?
     //
     // "with BOM handling" means that the UTF-16 BOM is translated to a UTF-8 BOM
     //
-    if let Some((enc, _)) = encoding_rs::Encoding::for_bom(&candidate) {
-        if [encoding_rs::UTF_16LE, encoding_rs::UTF_16BE].contains(&enc) {
-            let (translated, _had_malformed_sequences) =
-                enc.decode_without_bom_handling(&candidate);
-            return translated.into_owned().into_bytes();
-        }
-    }
-    candidate
+    let enc = utf16_encoding(candidate)?;
+    let (translated, _had_malformed_sequences) = enc.decode_without_bom_handling(candidate);
+    Some(translated.into_owned().into_bytes())
 }
 
 /// The first operand is read into memory in its entirety, but that's not
@@ -93,46 +248,165 @@ impl ExactSizeIterator for Remaining {
 
 /// `NextOperand` is the `Item` type for the `Remaining` iterator. For a given
 /// file path, the `reader` field is a reader for the file with that path, and
-/// `path_display` is the path formatted for use in error messages.
+/// `path_display` is the path formatted for use in error messages. For a
+/// `.zidx` file, `index` holds the index, and there are no lines to read.
 pub struct NextOperand {
     path_display: String,
     reader: Box<dyn io::BufRead>,
+    index: Option<SetIndex>,
 }
 
 /// The reader for a second or subsequent operand is a buffered reader with the
 /// ability to decode UTF-16 files. I think this results in double-buffering,
 /// with one buffer within the `DecodeReaderBytes` value, and another in the
 /// `BufReader` that wraps it. I don't know how to work around that.
+pub(crate) fn reader_for(path: &Path) -> Result<NextOperand> {
+    if is_index(path) {
+        let (path_display, reader) = (format!("{}", path.display()), Box::new(io::empty()));
+        return Ok(NextOperand { path_display, reader, index: Some(SetIndex::open(path)?) });
+    }
+    let (path_display, source) = source_for(path)?;
+    let reader = Box::new(io::BufReader::new(decoder(source)));
+    Ok(NextOperand { path_display, reader, index: None })
+}
+
+/// A reader for the first operand that, unlike `reader_for`, begins with a
+/// UTF-8 Byte Order Mark if the operand begins with a Byte Order Mark (UTF-8 or
+/// UTF-16), so that the caller can tell. Used by `stream::stream_union`, which
+/// can't read the first operand in advance.
+pub(crate) fn reader_keeping_bom(path: &Path) -> Result<NextOperand> {
+    let (path_display, mut source) = source_for(path)?;
+    let mut start = [0; 3];
+    let mut len = 0;
+    while len < start.len() {
+        match source.read(&mut start[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e).with_context(|| format!("Error reading file: {path_display}")),
+        }
+    }
+    let start = start[..len].to_vec();
+    let bom: &[u8] =
+        if encoding_rs::Encoding::for_bom(&start).is_some() { b"\xEF\xBB\xBF" } else { b"" };
+    let source = io::Cursor::new(start).chain(source);
+    let reader = Box::new(io::BufReader::new(bom.chain(decoder(source))));
+    Ok(NextOperand { path_display, reader, index: None })
+}
+
+/// A reader for the first operand, as `reader_for` would return, together
+/// with the `(bom, line_terminator)` pair that `ZetSet::new` would find for
+/// it. We find the pair by reading the operand's first line, which the
+/// reader then returns again, so this works for standard input, which we
+/// can't read twice. Used by the modes that don't read the first operand
+/// into memory (`--memory-limit`, `--hash-only`, and `--sorted`).
+pub(crate) fn first_with_output_info(
+    path: &Path,
+) -> Result<(NextOperand, &'static [u8], &'static [u8])> {
+    if is_index(path) {
+        // The caller will fail when it tries to read the index's lines
+        return Ok((reader_for(path)?, b"", b"\n"));
+    }
+    let mut operand = reader_keeping_bom(path)?;
+    let mut line = Vec::new();
+    operand.read_raw_line(&mut line)?;
+    let (bom, line_terminator) = output_info(&line);
+    line.drain(..bom.len());
+    let NextOperand { path_display, reader, index } = operand;
+    let reader = Box::new(io::Cursor::new(line).chain(reader));
+    Ok((NextOperand { path_display, reader, index }, bom, line_terminator))
+}
+
+/// The unbuffered input for `path`, and `path` formatted for use in error
+/// messages
 #[allow(trivial_casts)]
-fn reader_for(path: &Path) -> Result<NextOperand> {
-    fn decoder<R: Read>(f: R) -> DecodeReaderBytes<R, Vec<u8>> {
-        DecodeReaderBytesBuilder::new()
-            .bom_sniffing(true)
-            .strip_bom(true)
-            .utf8_passthru(true)
-            .build(f)
-    }
-    let (path_display, reader) = if use_stdin(path) {
-        let path_display = "<stdin>".to_string();
-        let reader = decoder(io::stdin().lock());
-        (path_display, Box::new(io::BufReader::new(reader)) as Box<dyn io::BufRead>)
+fn source_for(path: &Path) -> Result<(String, Box<dyn Read>)> {
+    if use_stdin(path) {
+        Ok(("<stdin>".to_string(), Box::new(io::stdin().lock()) as Box<dyn Read>))
     } else {
         let path_display = format!("{}", path.display());
-        let reader =
-            decoder(File::open(path).with_context(|| format!("Can't open file: {path_display}"))?);
-        (path_display, Box::new(io::BufReader::new(reader)) as Box<dyn io::BufRead>)
-    };
-    Ok(NextOperand { path_display, reader })
+        let file = File::open(path).with_context(|| format!("Can't open file: {path_display}"))?;
+        Ok((path_display, Box::new(file) as Box<dyn Read>))
+    }
+}
+
+/// Decode `source`, translating UTF-16 to UTF-8 and removing any Byte Order
+/// Mark
+fn decoder<R: Read>(source: R) -> DecodeReaderBytes<R, Vec<u8>> {
+    DecodeReaderBytesBuilder::new()
+        .bom_sniffing(true)
+        .strip_bom(true)
+        .utf8_passthru(true)
+        .build(source)
+}
+impl NextOperand {
+    /// The path, formatted for use in error messages
+    pub(crate) fn path_display(&self) -> &str {
+        &self.path_display
+    }
+
+    /// Fail if we're an index, whose lines can't be read
+    fn check_not_index(&self) -> Result<()> {
+        if self.index.is_some() {
+            bail!(
+                "{}: zet can't read the lines of an index; it can only look lines up in one \
+                 that's a later operand of `diff` or an operand of `intersect`, without \
+                 --hash-only, --memory-limit, --sorted, --stream, or --emit",
+                self.path_display
+            )
+        }
+        Ok(())
+    }
+
+    /// Read the next line into `line` (replacing its contents), without its
+    /// line terminator, as `for_byte_line` would. Returns `false` at the end
+    /// of the operand.
+    pub(crate) fn read_line(&mut self, line: &mut Vec<u8>) -> Result<bool> {
+        let read = self.read_raw_line(line)?;
+        trim_line_terminator(line);
+        Ok(read)
+    }
+
+    /// Like `read_line`, but leave the line terminator in place
+    pub(crate) fn read_raw_line(&mut self, line: &mut Vec<u8>) -> Result<bool> {
+        self.check_not_index()?;
+        line.clear();
+        let n = io::BufRead::read_until(&mut self.reader, b'\n', line)
+            .with_context(|| format!("Error reading file: {}", self.path_display))?;
+        Ok(n > 0)
+    }
 }
+/// Remove the `\n` or `\r\n` (if any) from the end of `line`
+pub(crate) fn trim_line_terminator(line: &mut Vec<u8>) {
+    if line.last() == Some(&b'\n') {
+        line.pop();
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+    }
+}
+
 impl LaterOperand for NextOperand {
-    /// A convenience wrapper around `bstr::for_byte_line`
+    fn index(&self) -> Option<&SetIndex> {
+        self.index.as_ref()
+    }
+
     fn for_byte_line(self, mut for_each_line: impl FnMut(&[u8])) -> Result<()> {
-        let NextOperand { mut reader, path_display } = self;
+        self.try_for_byte_line(|line| {
+            for_each_line(line);
+            ControlFlow::Continue(())
+        })
+    }
+
+    /// A convenience wrapper around `bstr::for_byte_line`
+    fn try_for_byte_line(
+        self,
+        mut for_each_line: impl FnMut(&[u8]) -> ControlFlow<()>,
+    ) -> Result<()> {
+        self.check_not_index()?;
+        let NextOperand { mut reader, path_display, .. } = self;
         reader
-            .for_byte_line(|line| {
-                for_each_line(line);
-                Ok(true)
-            })
+            .for_byte_line(|line| Ok(for_each_line(line).is_continue()))
             .with_context(|| format!("Error reading file: {path_display}"))?;
         Ok(())
     }
@@ -167,6 +441,34 @@ mod test {
         result
     }
 
+    #[test]
+    fn large_files_are_mapped_unless_they_need_translation() {
+        use assert_fs::{prelude::*, TempDir};
+        let dir = TempDir::new().unwrap();
+        let (plain, utf16) = (dir.child("plain.txt"), dir.child("utf16.txt"));
+        plain.write_str("a\nb\n").unwrap();
+        utf16.write_binary(&to_utf_16le("a\nb\n")).unwrap();
+
+        let mapped = FirstOperand::open(plain.path(), 0).unwrap();
+        assert!(matches!(mapped, FirstOperand::Mapped(_)));
+        assert_eq!(mapped.as_slice(), b"a\nb\n");
+        mapped.check_unchanged().unwrap();
+        let mut guarded = Vec::new();
+        mapped.guard(&mut guarded).write_all(b"result").unwrap();
+        assert_eq!(guarded, b"result");
+        plain.write_str("a\nb\nc\n").unwrap();
+        assert!(mapped.check_unchanged().is_err());
+        let mut guarded = Vec::new();
+        assert!(mapped.guard(&mut guarded).write_all(b"result").is_err());
+        assert!(guarded.is_empty());
+
+        let read = FirstOperand::open(utf16.path(), 0).unwrap();
+        assert!(matches!(read, FirstOperand::Read(_)));
+        assert_eq!(read.as_slice(), abominate("a\nb\n").as_bytes());
+        let small = FirstOperand::open(plain.path(), MMAP_THRESHOLD).unwrap();
+        assert!(matches!(small, FirstOperand::Read(_)));
+    }
+
     #[test]
     fn utf_16le_is_translated_to_utf8() {
         let expected = "The cute red crab\n jumps over the lazy blue gopher\n";
@@ -1,93 +1,339 @@
 //! Houses the `calculate` function
 //!
 use anyhow::{bail, Result};
+use std::cmp::Ordering;
 use std::fmt::Debug;
 
-use crate::args::OpName::{
-    self, Diff, Intersect, Multiple, MultipleByFile, Single, SingleByFile, Union,
-};
-use crate::set::{LaterOperand, ZetSet};
+use crate::budget::Budget;
+use crate::histogram::HistogramBy;
+use crate::order::{natural_cmp, Sequence, SortOrder};
+use crate::output::{write_records, OperandSet, Output, Provenance, Record, Selection};
+use crate::parallel::read_in_parallel;
+use crate::set::{Aggregate, Arena, IndexUpdates, LaterOperand, ZetSet};
+use Operation::{Diff, Intersect, Multiple, MultipleByFile, Single, SingleByFile, Union};
 
-#[derive(Clone, Copy, Debug)]
-pub enum LogType {
+/// A set operation
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Operation {
+    /// The lines present in every operand
+    Intersect,
+    /// The lines present in any operand
+    Union,
+    /// The lines present in the first operand but no other
+    Diff,
+    /// The lines present exactly once in the entire input
+    Single,
+    /// The lines present in exactly one operand
+    SingleByFile,
+    /// The lines present more than once in the entire input
+    Multiple,
+    /// The lines present in two or more operands
+    MultipleByFile,
+}
+
+/// The count to write with each line of the result, if any
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum CountMode {
+    /// The number of times the line appears in the input
     Lines,
+    /// The number of operands the line appears in
     Files,
+    /// No count
     None,
 }
+
+/// How to read the operands after the first
+#[derive(Clone, Debug)]
+pub struct Reading {
+    /// The number of threads to read operands with. With more than one
+    /// thread, several operands are read at once (see `read_in_parallel`),
+    /// but the result is the same as reading them one at a time.
+    pub threads: usize,
+    /// Keep only a hash of each line, rather than the line itself? (See
+    /// `hashed::calculate_hashed`.)
+    pub hash_only: bool,
+    /// With `hash_only`, check for hash collisions?
+    pub verify_hashes: bool,
+    /// Spill the input to temporary files, keeping roughly this many bytes of
+    /// distinct lines in memory at a time? (See `spill::calculate_spilled`.)
+    pub memory_limit: Option<u64>,
+    /// Is every operand already sorted? (See `sorted::calculate_sorted`.)
+    pub sorted: bool,
+    /// Write each line of a `Union` as soon as it's first seen? (See
+    /// `stream::stream_union`.)
+    pub stream: bool,
+    /// The operand to read first, if it isn't the first operand on the
+    /// command line (See `plan::plan`.)
+    pub base: Option<Base>,
+    /// The most memory, in bytes, the `ZetSet` and the first operand may use.
+    /// (See `budget::Budget`.)
+    pub max_memory: Option<u64>,
+}
+impl Default for Reading {
+    fn default() -> Self {
+        Reading {
+            threads: 1,
+            hash_only: false,
+            verify_hashes: false,
+            memory_limit: None,
+            sorted: false,
+            stream: false,
+            base: None,
+            max_memory: None,
+        }
+    }
+}
+
+/// An operand to read first, in place of the first operand on the command
+/// line. Reading the smallest operand first keeps the `ZetSet` for `Intersect`
+/// small. The result is the same as reading the operands in command-line
+/// order, so we need the first operand's byte order mark and line terminator
+/// to write it with.
+#[derive(Clone, Copy, Debug)]
+pub struct Base {
+    /// The base operand's position on the command line, counting from `0`
+    pub position: usize,
+    /// The byte order mark of the first operand on the command line, or `b""`
+    pub bom: &'static [u8],
+    /// The line terminator of the first operand on the command line
+    pub line_terminator: &'static [u8],
+}
+
+/// The operands of a set operation, the arena to store lines from the second
+/// and later operands in, the number of threads to read those operands with,
+/// the command-line position of `first_operand` (see `Base`), and the memory
+/// budget
+struct Input<'data, I> {
+    first_operand: &'data [u8],
+    arena: &'data Arena,
+    rest: I,
+    threads: usize,
+    base: u32,
+    budget: Budget,
+}
 /// Calculates and prints the set operation named by `operation`. Each file in `files`
 /// is treated as a set of lines:
 ///
-/// * `OpName::Union` prints the lines that occur in any file,
-/// * `OpName::Intersect` prints the lines that occur in all files,
-/// * `OpName::Diff` prints the lines that occur in the first file and no other,
-/// * `OpName::Single` prints the lines that occur once in exactly in the input,
-/// * `OpName::Multiple` prints the lines that occur more than once in the input,
-/// * `OpName::SingleByFile` prints the lines that occur in exactly one file, and
-/// * `OpName::MultipleByFile` prints the lines that occur in more than one file.
+/// * `Operation::Union` prints the lines that occur in any file,
+/// * `Operation::Intersect` prints the lines that occur in all files,
+/// * `Operation::Diff` prints the lines that occur in the first file and no other,
+/// * `Operation::Single` prints the lines that occur once in exactly in the input,
+/// * `Operation::Multiple` prints the lines that occur more than once in the input,
+/// * `Operation::SingleByFile` prints the lines that occur in exactly one file, and
+/// * `Operation::MultipleByFile` prints the lines that occur in more than one file.
 ///
 /// The `log_type` operand specifies whether `calculate` should print the number
-/// of times each line appears in the input (`LogType::Lines`), the number of
-/// files in which each line appears (`LogType::Files`), or neither
-/// (`LogType::None`).
+/// of times each line appears in the input (`CountMode::Lines`), the number of
+/// files in which each line appears (`CountMode::Files`), or neither
+/// (`CountMode::None`).
+///
+/// The `output` operand specifies the output format, and whether to report
+/// where each line first appeared. The `reading` operand specifies how to read
+/// the operands. If `reading.base` is set, `first_operand` is the operand at
+/// that position on the command line, and `rest` holds the others in
+/// command-line order. If `reading.max_memory` is set and the `ZetSet` would
+/// use more memory than that, `calculate` returns an `OverBudget` error before
+/// writing anything.
 ///
 pub fn calculate<O: LaterOperand>(
-    operation: OpName,
-    log_type: LogType,
+    operation: Operation,
+    log_type: CountMode,
+    output: &Output,
+    reading: &Reading,
     first_operand: &[u8],
-    rest: impl ExactSizeIterator<Item = Result<O>>,
+    rest: impl ExactSizeIterator<Item = Result<O>> + Send,
     out: impl std::io::Write,
 ) -> Result<()> {
-    let number_of_operands = rest.len() + 1; // + 1 because first_operand is an operand
-    if number_of_operands > u32::MAX as usize {
-        bail!("Zet can't handle more than {} input files", u32::MAX)
-        // Since we have <= u32::MAX operands, the `next_file` method can't overflow and we can use
-        // wrapping_add
+    output.check_operands(rest.len() + 1)?;
+    let tally = output.needs_tally() || reading.base.is_some();
+    let out = Sink { log_type, output, base: reading.base, out };
+    calculate_into(operation, log_type, tally, reading, first_operand, rest, out)
+}
+
+/// Calculates the set operation named by `operation`, as `calculate` does, and
+/// calls `visit` with a `Record` for each line of the result, in the order
+/// the lines first appear in the input. Every `Record` has both counts and the
+/// line's provenance.
+pub fn visit<O: LaterOperand>(
+    operation: Operation,
+    reading: &Reading,
+    first_operand: &[u8],
+    rest: impl ExactSizeIterator<Item = Result<O>> + Send,
+    visit: impl FnMut(Record<'_>),
+) -> Result<()> {
+    let out = Visitor { base: reading.base, visit };
+    calculate_into(operation, CountMode::None, true, reading, first_operand, rest, out)
+}
+
+/// Calculates the set operation named by `operation`, and hands the result to
+/// `out`. With `tally`, we use `Tallied` bookkeeping (see below).
+fn calculate_into<O: LaterOperand>(
+    operation: Operation,
+    log_type: CountMode,
+    tally: bool,
+    reading: &Reading,
+    first_operand: &[u8],
+    rest: impl ExactSizeIterator<Item = Result<O>> + Send,
+    out: impl Consume,
+) -> Result<()> {
+    check_number_of_operands(rest.len())?;
+    if reading.base.is_some() {
+        if operation == Diff {
+            bail!(
+                "`diff` keeps only lines of its first operand, so it must read that operand first"
+            )
+        }
+        if reading.threads > 1 {
+            bail!("Can't read a --base operand first with more than one thread")
+        }
+    }
+    if reading.max_memory.is_some() && reading.threads > 1 {
+        bail!("Can't keep to a memory limit with more than one thread")
+    }
+    let base = reading.base.map_or(Ok(0), |base| u32::try_from(base.position))?;
+    let arena = Arena::new();
+    let budget = Budget::new(reading.max_memory);
+    let input =
+        Input { first_operand, arena: &arena, rest, threads: reading.threads, base, budget };
+
+    // Structured output and provenance need more information than the
+    // specialized bookkeeping types below keep, so we use `Tallied`
+    // bookkeeping, which keeps all of it, sifting with the same type we'd
+    // otherwise use. So does reading a `Base` operand first, since we put the
+    // result back in command-line order by where each line first appeared,
+    // and so does a `Visitor`, whose `Record`s have every count.
+    if tally {
+        return match operation {
+            Union => union::<Tallied<Unsifted>, O>(input, out),
+            Diff => diff::<Tallied<Files>, O>(input, out),
+            Intersect => intersect::<Tallied<Files>, O>(input, out),
+            Single => keep_single::<Tallied<Lines>, O>(input, out),
+            Multiple => keep_multiple::<Tallied<Lines>, O>(input, out),
+            SingleByFile => keep_single::<Tallied<Files>, O>(input, out),
+            MultipleByFile => keep_multiple::<Tallied<Files>, O>(input, out),
+        };
     }
     match log_type {
-        LogType::None => match operation {
-            Union => union::<Unsifted, O>(first_operand, rest, out),
-            Diff => diff::<Files, O>(first_operand, rest, out),
-            Intersect => intersect::<Files, O>(first_operand, rest, out),
-            Single => keep_single::<Lines, O>(first_operand, rest, out),
-            Multiple => keep_multiple::<Lines, O>(first_operand, rest, out),
-            SingleByFile => keep_single::<Files, O>(first_operand, rest, out),
-            MultipleByFile => keep_multiple::<Files, O>(first_operand, rest, out),
+        CountMode::None => match operation {
+            Union => union::<Unsifted, O>(input, out),
+            Diff => diff::<Files, O>(input, out),
+            Intersect => intersect::<Files, O>(input, out),
+            Single => keep_single::<Lines, O>(input, out),
+            Multiple => keep_multiple::<Lines, O>(input, out),
+            SingleByFile => keep_single::<Files, O>(input, out),
+            MultipleByFile => keep_multiple::<Files, O>(input, out),
         },
 
-        // When `log_type` is `LogType::Lines` and `operation` is `Single` or
+        // When `log_type` is `CountMode::Lines` and `operation` is `Single` or
         // `Multiple`, both logging and selection use `Lines`. Since
         // `SiftLog<Lines, Lines>` would do duplicate bookkeeping, we just
         // use `Lines` by itself.
-        LogType::Lines => match operation {
-            Union => union::<Log<Lines>, O>(first_operand, rest, out),
-            Diff => diff::<SiftLog<Files, Lines>, O>(first_operand, rest, out),
-            Intersect => intersect::<SiftLog<Files, Lines>, O>(first_operand, rest, out),
-            Single => keep_single::<Log<Lines>, O>(first_operand, rest, out),
-            Multiple => keep_multiple::<Log<Lines>, O>(first_operand, rest, out),
-            SingleByFile => keep_single::<SiftLog<Files, Lines>, O>(first_operand, rest, out),
-            MultipleByFile => keep_multiple::<SiftLog<Files, Lines>, O>(first_operand, rest, out),
+        CountMode::Lines => match operation {
+            Union => union::<Log<Lines>, O>(input, out),
+            Diff => diff::<SiftLog<Files, Lines>, O>(input, out),
+            Intersect => intersect::<SiftLog<Files, Lines>, O>(input, out),
+            Single => keep_single::<Log<Lines>, O>(input, out),
+            Multiple => keep_multiple::<Log<Lines>, O>(input, out),
+            SingleByFile => keep_single::<SiftLog<Files, Lines>, O>(input, out),
+            MultipleByFile => keep_multiple::<SiftLog<Files, Lines>, O>(input, out),
         },
 
         // Similarly, we don't want to use `SiftLog<Files, Files>` bookkeeping
         // values, so we use `Log<Files>` by itself when `log_type` is
-        // LogType::Files` and `operation` is `SingleByFile` or
+        // CountMode::Files` and `operation` is `SingleByFile` or
         // `MultipleByFile`.
         //
         // And we use `Log<Lines>` for `Single`, rather than `SiftLog<Lines,
         // Files>`, since the number reported for `Single` will always be 1 — a
         // line appearing only once can appear in only one file.
-        LogType::Files => match operation {
-            Union => union::<Log<Files>, O>(first_operand, rest, out),
-            Diff => diff::<Log<Files>, O>(first_operand, rest, out),
-            Intersect => intersect::<Log<Files>, O>(first_operand, rest, out),
-            Single => keep_single::<Log<Lines>, O>(first_operand, rest, out),
-            Multiple => keep_multiple::<SiftLog<Lines, Files>, O>(first_operand, rest, out),
-            SingleByFile => keep_single::<Log<Files>, O>(first_operand, rest, out),
-            MultipleByFile => keep_multiple::<Log<Files>, O>(first_operand, rest, out),
+        CountMode::Files => match operation {
+            Union => union::<Log<Files>, O>(input, out),
+            Diff => diff::<Log<Files>, O>(input, out),
+            Intersect => intersect::<Log<Files>, O>(input, out),
+            Single => keep_single::<Log<Lines>, O>(input, out),
+            Multiple => keep_multiple::<SiftLog<Lines, Files>, O>(input, out),
+            SingleByFile => keep_single::<Log<Files>, O>(input, out),
+            MultipleByFile => keep_multiple::<Log<Files>, O>(input, out),
         },
     }
 }
 
+/// Calculates several set operations over the same operands, reading each
+/// operand only once, and writes the result of each operation to its own
+/// output. The `log_type`, `output`, and `reading` operands apply to every
+/// operation, as they do for `calculate`.
+///
+/// Rather than sifting the `ZetSet` with the bookkeeping type each operation
+/// would use by itself, we collect every line of the input with `Tallied`
+/// bookkeeping, which keeps enough information to decide membership for any
+/// operation (see `belongs_in`), and write each operation's view of the set in
+/// turn. Note that this keeps every input line in memory, even when all the
+/// operations are `Diff` or `Intersect`, which by themselves need only the
+/// lines of the first operand.
+pub fn calculate_many<O: LaterOperand, W: std::io::Write>(
+    operations: Vec<(Operation, W)>,
+    log_type: CountMode,
+    output: &Output,
+    reading: &Reading,
+    first_operand: &[u8],
+    rest: impl ExactSizeIterator<Item = Result<O>> + Send,
+) -> Result<()> {
+    output.check_operands(rest.len() + 1)?;
+    let all_files = check_number_of_operands(rest.len())?;
+    let arena = Arena::new();
+    if reading.max_memory.is_some() && reading.threads > 1 {
+        bail!("Can't keep to a memory limit with more than one thread")
+    }
+    let budget = Budget::new(reading.max_memory);
+    let input =
+        Input { first_operand, arena: &arena, rest, threads: reading.threads, base: 0, budget };
+    let set = every_line::<Tallied<Unsifted>, O>(input)?;
+    let sequence = output_sequence(&set, output);
+    for (operation, out) in operations {
+        let mut keep: Vec<bool> =
+            set.values().map(|&item| belongs_in(operation, item, all_files)).collect();
+        keep_extremes(&set, &mut keep, output);
+        let view = sequence.iter().filter(|&&index| keep[index]).map(|&index| set.get_index(index));
+        let out = Sink { log_type, output, base: None, out };
+        write_view(&set, view, out)?;
+    }
+    Ok(())
+}
+
+/// Bail if there are more operands than a `u32` can count; otherwise return
+/// the number of operands. There are `rest_len` operands after the first.
+fn check_number_of_operands(rest_len: usize) -> Result<u32> {
+    let number_of_operands = rest_len + 1; // + 1 because first_operand is an operand
+    if number_of_operands > u32::MAX as usize {
+        bail!("Zet can't handle more than {} input files", u32::MAX)
+        // Since we have <= u32::MAX operands, the `next_file` method can't overflow and we can use
+        // wrapping_add
+    }
+    Ok(u32::try_from(number_of_operands)?)
+}
+
+/// Does a line whose bookkeeping item is `item` belong in the result of
+/// `operation`? This is the same decision the `retain` calls in `keep_single`,
+/// `keep_multiple`, `diff`, and `intersect` make, but made from the `Tally` of
+/// a set that includes every input line. A line is in the first operand if its
+/// first appearance was there.
+pub(crate) fn belongs_in<Sifted: Bookkeeping>(
+    operation: Operation,
+    item: Tallied<Sifted>,
+    all_files: u32,
+) -> bool {
+    let Tally { lines: Lines(lines), files: Files { files_seen, .. }, first, .. } = item.tally;
+    match operation {
+        Union => true,
+        Diff => first.file_number == 0 && files_seen == 1,
+        Intersect => files_seen == all_files,
+        Single => lines == 1,
+        Multiple => lines > 1,
+        SingleByFile => files_seen == 1,
+        MultipleByFile => files_seen > 1,
+    }
+}
+
 /// A `ZetSet` is an ordered set of lines where each line from the input file(s)
 /// occurs once in the `ZetSet`, and each line has an associated `Bookkeeping`
 /// value that we use to determine whether to retain the line in the output, and
@@ -96,8 +342,11 @@ pub fn calculate<O: LaterOperand>(
 /// which the line occurs).
 ///
 /// The `Bookkeeping` trait specifies the kind of types that can serve as the
-/// bookkeeping values for a `ZetSet`, and defines a default `output_zet_set`
-/// method to print the lines without a count.
+/// bookkeeping values for a `ZetSet` in a set operation, and defines a default
+/// `output_zet_set` method to print the lines without a count. Bookkeeping
+/// types are `Aggregate`s, whose `update_with` method says how a line's value
+/// changes when it's seen again; `Bookkeeping` adds what set operations need:
+/// a starting value, a retention value, and counts to report.
 ///
 /// There are seven `Bookkeeping` types. The `Unsifted`, `Lines`, and `Files`
 /// types are used for "sifting" — after all files have been processed, we look
@@ -124,7 +373,7 @@ pub fn calculate<O: LaterOperand>(
 /// files seen and log the number of lines seen.  And we could use
 /// `SiftLog<Lines, Files>` to print only lines occuring multiple times, while
 /// printing the number of files each line occurs in.
-pub(crate) trait Bookkeeping: Copy + PartialEq + Debug {
+pub(crate) trait Bookkeeping: Aggregate + PartialEq + Debug + Send + Sync {
     /// The initial bookkeeping value for each line in the first operand.
     /// Usually keeps track of lines and/or files seen.
     fn new() -> Self;
@@ -132,20 +381,48 @@ pub(crate) trait Bookkeeping: Copy + PartialEq + Debug {
     /// Increment the bookkeeping item's `n`th file field (if it has one)
     fn next_file(&mut self);
 
-    /// Here `other` is the value that would have been inserted for a
-    /// newly-encountered line. Used to update the bookkeeping values of lines
-    /// already present in the `ZetSet`.
-    fn update_with(&mut self, other: Self);
+    /// Called after `next_file` (and before the first operand) with the
+    /// command-line position of the operand about to be read, which differs
+    /// from the number of operands read so far when there's a `Base` operand.
+    /// The provided implementation does nothing; only bookkeeping types that
+    /// record provenance need to override it.
+    fn renumber_file(&mut self, _file_number: u32) {}
 
     /// The value to be used in closure passed to the `ZetSet`'s `retain`
     /// method.
     fn retention_value(self) -> u32;
 
+    /// The number of times the line has been seen, if we're keeping track.
+    fn line_count(self) -> Option<u32> {
+        None
+    }
+
+    /// The number of files the line has been seen in, if we're keeping track.
+    fn file_count(self) -> Option<u32> {
+        None
+    }
+
+    /// Where the line was first seen, if we're keeping track.
+    fn provenance(self) -> Option<Provenance> {
+        None
+    }
+
+    /// Where the line was last seen, if we're keeping track.
+    fn last_seen(self) -> Option<Provenance> {
+        None
+    }
+
+    /// The operands the line has been seen in, as an `OperandSet`, if we're
+    /// keeping track.
+    fn operands(self) -> Option<OperandSet> {
+        None
+    }
+
     /// Output the `ZetSet`. The provided implementation doesn't log a count of
     /// lines or files, so must be overridden by types that do loggging.
     fn output_zet_set(set: &ZetSet<Self>, mut out: impl std::io::Write) -> Result<()> {
         out.write_all(set.bom)?;
-        for line in set.keys() {
+        for (line, _) in set.entries() {
             out.write_all(line)?;
             out.write_all(set.line_terminator)?;
         }
@@ -172,50 +449,57 @@ trait Loggable: Bookkeeping {
 /// `every_line`'s caller can then use `set.retain()` to examine the each line's
 /// bookkeeping item to decide whether or not it belongs in the set.
 fn every_line<B: Bookkeeping, O: LaterOperand>(
-    first_operand: &[u8],
-    rest: impl Iterator<Item = Result<O>>,
-) -> Result<ZetSet<B>> {
+    input: Input<'_, impl Iterator<Item = Result<O>> + Send>,
+) -> Result<ZetSet<'_, B>> {
+    let Input { first_operand, arena, rest, threads, base, mut budget } = input;
     let mut item = B::new();
-    let mut set = ZetSet::new(first_operand, item);
-    for operand in rest {
-        item.next_file();
-        set.insert_or_update(operand?, item)?;
+    item.renumber_file(base);
+    let mut set = ZetSet::new(first_operand, item, &mut budget);
+    budget.check(base)?;
+    if threads > 1 {
+        let merge = |lines| set.merge(lines, arena);
+        read_in_parallel(rest, item, threads, ZetSet::from_operand, merge)?;
+    } else {
+        for (read, operand) in (1..).zip(rest) {
+            item.next_file();
+            let position = command_line_position(read, base);
+            item.renumber_file(position);
+            set.insert_or_update(operand?, Some(arena), &mut budget, item)?;
+            budget.check(position)?;
+        }
     }
     Ok(set)
 }
 
 /// `Union` collects every line, so we don't need to call `retain`
 fn union<B: Bookkeeping, O: LaterOperand>(
-    first_operand: &[u8],
-    rest: impl Iterator<Item = Result<O>>,
-    out: impl std::io::Write,
+    input: Input<'_, impl Iterator<Item = Result<O>> + Send>,
+    out: impl Consume,
 ) -> Result<()> {
-    let set = every_line::<B, O>(first_operand, rest)?;
-    output_and_discard(set, out)
+    let set = every_line::<B, O>(input)?;
+    out.consume(set)
 }
 
 /// `Single` and `SingleByFile` retain those lines where the relevant count is
 /// `1`.
 fn keep_single<B: Bookkeeping, O: LaterOperand>(
-    first_operand: &[u8],
-    rest: impl Iterator<Item = Result<O>>,
-    out: impl std::io::Write,
+    input: Input<'_, impl Iterator<Item = Result<O>> + Send>,
+    out: impl Consume,
 ) -> Result<()> {
-    let mut set = every_line::<B, O>(first_operand, rest)?;
+    let mut set = every_line::<B, O>(input)?;
     set.retain(|occurences| occurences == 1);
-    output_and_discard(set, out)
+    out.consume(set)
 }
 
 /// `Multiple` and `MultipleByFile` retain those lines where the relevant count is
 /// greater than `1`.
 fn keep_multiple<B: Bookkeeping, O: LaterOperand>(
-    first_operand: &[u8],
-    rest: impl Iterator<Item = Result<O>>,
-    out: impl std::io::Write,
+    input: Input<'_, impl Iterator<Item = Result<O>> + Send>,
+    out: impl Consume,
 ) -> Result<()> {
-    let mut set = every_line::<B, O>(first_operand, rest)?;
+    let mut set = every_line::<B, O>(input)?;
     set.retain(|occurences| occurences > 1);
-    output_and_discard(set, out)
+    out.consume(set)
 }
 
 /// For the "subtractive" operations `Diff` and `Intersect`, we insert only
@@ -231,65 +515,288 @@ fn keep_multiple<B: Bookkeeping, O: LaterOperand>(
 /// the each line's bookkeeping item to decide whether or not it belongs in the
 /// set.
 fn first_file_lines<B: Bookkeeping, O: LaterOperand>(
-    first_operand: &[u8],
-    rest: impl Iterator<Item = Result<O>>,
-) -> Result<ZetSet<B>> {
+    input: Input<'_, impl Iterator<Item = Result<O>> + Send>,
+) -> Result<ZetSet<'_, B>> {
+    let Input { first_operand, rest, threads, base, mut budget, .. } = input;
     let mut item = B::new();
-    let mut set = ZetSet::new(first_operand, item);
-    for operand in rest {
-        item.next_file();
-        set.update_if_present(operand?, item)?;
+    item.renumber_file(base);
+    let mut set = ZetSet::new(first_operand, item, &mut budget);
+    budget.check(base)?;
+    if threads > 1 {
+        // The reading threads look lines up in `set` while we merge their
+        // updates, so we merge into a copy of the set's bookkeeping items.
+        let mut items: Vec<B> = set.values().copied().collect();
+        let merge = |updates: IndexUpdates<B>| {
+            for (index, update) in updates {
+                items[index].merge_with(update);
+            }
+        };
+        let updates_for = |operand, item| set.updates_if_present(operand, item);
+        read_in_parallel(rest, item, threads, updates_for, merge)?;
+        set.replace_values(items);
+    } else {
+        for (read, operand) in (1..).zip(rest) {
+            item.next_file();
+            item.renumber_file(command_line_position(read, base));
+            set.update_if_present(operand?, item)?;
+        }
     }
     Ok(set)
 }
 
+/// The command-line position of the operand we read `read`th, both counting
+/// from `0`, when we read the operand at position `base` first and the others
+/// in command-line order.
+fn command_line_position(read: u32, base: u32) -> u32 {
+    match read {
+        0 => base,
+        read if read <= base => read - 1,
+        read => read,
+    }
+}
+
 /// `Diff` retains only those lines seen only in the first file. Since
 /// `first_file_lines` only includes lines from the first file, we can
 /// equivalently retain those lines whose file count is `1`.
 fn diff<B: Bookkeeping, O: LaterOperand>(
-    first_operand: &[u8],
-    rest: impl Iterator<Item = Result<O>>,
-    out: impl std::io::Write,
+    input: Input<'_, impl Iterator<Item = Result<O>> + Send>,
+    out: impl Consume,
 ) -> Result<()> {
     let first_file_only = 1;
-    let mut set = first_file_lines::<B, O>(first_operand, rest)?;
+    let mut set = first_file_lines::<B, O>(input)?;
     set.retain(|files_containing_line| files_containing_line == first_file_only);
-    output_and_discard(set, out)
+    out.consume(set)
 }
 
 /// `Intersect` retains only those lines whose file count is the same as the
 /// number of input files.
 fn intersect<B: Bookkeeping, O: LaterOperand>(
-    first_operand: &[u8],
-    rest: impl ExactSizeIterator<Item = Result<O>>,
-    out: impl std::io::Write,
+    input: Input<'_, impl ExactSizeIterator<Item = Result<O>> + Send>,
+    out: impl Consume,
 ) -> Result<()> {
-    let all_files = u32::try_from(rest.len() + 1)?;
-    let mut set = first_file_lines::<B, O>(first_operand, rest)?;
+    let all_files = u32::try_from(input.rest.len() + 1)?;
+    let mut set = first_file_lines::<B, O>(input)?;
     set.retain(|files_containing_line| files_containing_line == all_files);
-    output_and_discard(set, out)
+    out.consume(set)
 }
 
-/// When we've finished constructing the `ZetSet`, we write its lines to our
-/// output and exit the program.
-fn output_and_discard<B: Bookkeeping>(set: ZetSet<B>, out: impl std::io::Write) -> Result<()> {
-    B::output_zet_set(&set, out)?;
-    std::mem::forget(set); // Slightly faster to just abandon this, since we're about to exit.
-                           // Thanks to [Karolin Varner](https://github.com/koraa)'s huniq
-    Ok(())
+/// What `calculate_into` does with the `ZetSet` it calculates: a `Sink` writes
+/// it, and a `Visitor` passes a `Record` for each of its lines to a closure.
+trait Consume {
+    fn consume<B: Bookkeeping>(self, set: ZetSet<B>) -> Result<()>;
+}
+
+/// A `Sink` bundles the output stream with the information needed to decide
+/// how to write to it.
+struct Sink<'a, W: std::io::Write> {
+    log_type: CountMode,
+    output: &'a Output,
+    base: Option<Base>,
+    out: W,
+}
+
+/// When we've finished constructing the `ZetSet`, we select its most or
+/// least frequent lines and sort it if asked to, then write its lines to our
+/// output.
+impl<W: std::io::Write> Consume for Sink<'_, W> {
+    fn consume<B: Bookkeeping>(self, mut set: ZetSet<B>) -> Result<()> {
+        if let Some(base) = self.base {
+            restore_command_line_order(&mut set, base);
+        }
+        select_extremes(&mut set, self.output);
+        sort_zet_set(&mut set, self.output);
+        if self.output.needs_tally() || self.base.is_some() {
+            output_zet_set_structured(&set, self)
+        } else {
+            B::output_zet_set(&set, self.out)
+        }
+    }
+}
+
+/// A `Visitor` calls `visit` with the `Record` of each line of the `ZetSet`,
+/// which has `Tallied` bookkeeping.
+struct Visitor<F: FnMut(Record<'_>)> {
+    base: Option<Base>,
+    visit: F,
+}
+impl<F: FnMut(Record<'_>)> Consume for Visitor<F> {
+    fn consume<B: Bookkeeping>(mut self, mut set: ZetSet<B>) -> Result<()> {
+        if let Some(base) = self.base {
+            restore_command_line_order(&mut set, base);
+        }
+        set.entries().map(|(line, item)| record(line, item)).for_each(&mut self.visit);
+        Ok(())
+    }
+}
+
+/// The `Record` of a line whose bookkeeping item is `item`, with whichever
+/// counts and provenance `item` keeps track of
+pub(crate) fn record<B: Bookkeeping>(line: &[u8], item: B) -> Record<'_> {
+    Record {
+        line,
+        count_lines: item.line_count(),
+        count_files: item.file_count(),
+        provenance: item.provenance(),
+        operands: item.operands(),
+    }
+}
+
+/// When we've read a `Base` operand first, the `ZetSet`'s lines are in the
+/// order we read them, and its byte order mark and line terminator are the base
+/// operand's. Put the lines in the order they first appear in the operands in
+/// command-line order, and use the first operand's byte order mark and line
+/// terminator, so the result is the same as if we'd read the first operand
+/// first. (`Tallied` bookkeeping records each line's earliest provenance.)
+fn restore_command_line_order<B: Bookkeeping>(set: &mut ZetSet<B>, base: Base) {
+    set.sort_by(|_, a, _, b| a.provenance().cmp(&b.provenance()));
+    set.bom = base.bom;
+    set.line_terminator = base.line_terminator;
+}
+
+/// For `--top n` and `--bottom n`, retain only the `n` lines of the (already
+/// sifted) `ZetSet` with the highest or lowest count. The retained lines keep
+/// their order; `sort_zet_set` can reorder them afterwards.
+fn select_extremes<B: Bookkeeping>(set: &mut ZetSet<B>, output: &Output) {
+    if output.select.is_some() {
+        let mut keep = vec![true; set.len()];
+        keep_extremes(set, &mut keep, output);
+        set.retain_indices(&keep);
+    }
+}
+
+/// Narrow `keep`, which has one entry for each line of `set`, to the `n` lines
+/// with the highest or lowest count, as requested by `output.select`,
+/// preferring lines seen earlier when counts are tied. We use
+/// `select_nth_unstable_by` rather than a full sort, so the cost is linear in
+/// the size of the set.
+fn keep_extremes<B: Bookkeeping>(set: &ZetSet<B>, keep: &mut [bool], output: &Output) {
+    let Some(selection) = output.select else { return };
+    let (n, top) = match selection {
+        Selection::Top(n) => (n, true),
+        Selection::Bottom(n) => (n, false),
+    };
+    let count = |item: B| {
+        let count = if output.select_by_files { item.file_count() } else { item.line_count() };
+        count.unwrap_or(0)
+    };
+    let mut ranked: Vec<(u32, usize)> = set
+        .values()
+        .enumerate()
+        .filter(|&(index, _)| keep[index])
+        .map(|(index, &item)| (count(item), index))
+        .collect();
+    if n >= ranked.len() {
+        return;
+    }
+    keep.fill(false);
+    if n > 0 {
+        ranked.select_nth_unstable_by(n - 1, |a, b| {
+            let by_count = if top { b.0.cmp(&a.0) } else { a.0.cmp(&b.0) };
+            by_count.then(a.1.cmp(&b.1))
+        });
+        for &(_, index) in &ranked[..n] {
+            keep[index] = true;
+        }
+    }
+}
+
+/// The output order requested by `output.sort`, `output.order`, and
+/// `output.reverse`. The sort is stable, so lines that compare equal stay in
+/// order of first appearance — or with `Sequence::LastSeen`, are put in order
+/// of last appearance. With `SortOrder::FirstSeen`, the sequence is the whole
+/// order, and `reverse` reverses it; otherwise `reverse` reverses the sort, and
+/// the sequence only breaks ties. The `LastSeen` sequence and the `Count` and
+/// `Files` sort orders need values that only `Tallied` bookkeeping provides;
+/// `Output::needs_tally` makes sure we have them.
+///
+/// A line's last appearance is recorded in its bookkeeping item as the
+/// position (file number and line number) where it was last seen, so moving a
+/// line to its latest position costs us one comparison per sort step, rather
+/// than removing and re-inserting the line each time it's seen.
+struct LineOrder {
+    sort: SortOrder,
+    order: Sequence,
+    reverse: bool,
+}
+impl LineOrder {
+    fn new(output: &Output) -> Self {
+        LineOrder { sort: output.sort, order: output.order, reverse: output.reverse }
+    }
+
+    /// Is this just first-seen order, possibly reversed? Then we needn't sort.
+    fn is_first_seen(&self) -> bool {
+        self.sort == SortOrder::FirstSeen && self.order == Sequence::FirstSeen
+    }
+
+    fn cmp<B: Bookkeeping>(&self, a_line: &[u8], a: B, b_line: &[u8], b: B) -> Ordering {
+        let by_sequence = match self.order {
+            Sequence::FirstSeen => Ordering::Equal,
+            Sequence::LastSeen => a.last_seen().cmp(&b.last_seen()),
+        };
+        let by_sort = match self.sort {
+            SortOrder::FirstSeen => Ordering::Equal,
+            SortOrder::Lexical => a_line.cmp(b_line),
+            SortOrder::Natural => natural_cmp(a_line, b_line),
+            SortOrder::Count => b.line_count().cmp(&a.line_count()),
+            SortOrder::Files => b.file_count().cmp(&a.file_count()),
+        };
+        if self.sort == SortOrder::FirstSeen {
+            if self.reverse {
+                by_sequence.reverse()
+            } else {
+                by_sequence
+            }
+        } else if self.reverse {
+            by_sort.reverse().then(by_sequence)
+        } else {
+            by_sort.then(by_sequence)
+        }
+    }
+}
+
+/// Reorder the `ZetSet` as requested by `output` — see `LineOrder`.
+fn sort_zet_set<B: Bookkeeping>(set: &mut ZetSet<B>, output: &Output) {
+    let line_order = LineOrder::new(output);
+    if line_order.is_first_seen() {
+        if line_order.reverse {
+            set.reverse();
+        }
+        return;
+    }
+    set.sort_by(|a_line, a, b_line, b| line_order.cmp(a_line, a, b_line, b));
+}
+
+/// Like `sort_zet_set`, but rather than reordering the set itself, returns the
+/// indices of its lines in the requested order.
+fn output_sequence<B: Bookkeeping>(set: &ZetSet<B>, output: &Output) -> Vec<usize> {
+    let line_order = LineOrder::new(output);
+    let mut sequence: Vec<usize> = (0..set.len()).collect();
+    if line_order.is_first_seen() {
+        if line_order.reverse {
+            sequence.reverse();
+        }
+    } else {
+        sequence.sort_by(|&a, &b| {
+            let ((a_line, a), (b_line, b)) = (set.get_index(a), set.get_index(b));
+            line_order.cmp(a_line, a, b_line, b)
+        });
+    }
+    sequence
 }
 
 /// We use the `Unsifted` struct for the `Union` operation when logging isn't needed.
 /// `Union` includes every line seen and doesn't need bookkeeping for anything
 /// but such logging.
 #[derive(Clone, Copy, PartialEq, Debug)]
-struct Unsifted();
+pub struct Unsifted();
+impl Aggregate for Unsifted {
+    fn update_with(&mut self, _other: Self) {}
+}
 impl Bookkeeping for Unsifted {
     fn new() -> Self {
         Unsifted()
     }
     fn next_file(&mut self) {}
-    fn update_with(&mut self, _other: Self) {}
     fn retention_value(self) -> u32 {
         0
     }
@@ -306,7 +813,34 @@ impl Bookkeeping for Unsifted {
 /// overflow for lines that appear `u32::MAX` times or more than to stop `zet`
 /// completely.
 #[derive(Clone, Copy, PartialEq, Debug)]
-struct Lines(u32);
+pub struct Lines(u32);
+impl Lines {
+    /// The state of a line seen once, to be given with an operand
+    #[must_use]
+    pub fn once() -> Self {
+        Lines(1)
+    }
+
+    /// The number of times the line has been seen
+    #[must_use]
+    pub fn count(self) -> u32 {
+        self.0
+    }
+}
+impl Aggregate for Lines {
+    /// When `update_with` is called, it means we've seen the line an additional
+    /// time.  We ignore `_other` and just increment our line count (with
+    /// `saturating_add(1)` so we don't wrap around.
+    fn update_with(&mut self, _other: Self) {
+        self.0 = self.0.saturating_add(1);
+    }
+
+    /// When merging, `other` counts the line's appearances in a later operand,
+    /// so we add it to ours — again with `saturating_add`.
+    fn merge_with(&mut self, other: Self) {
+        self.0 = self.0.saturating_add(other.0);
+    }
+}
 impl Bookkeeping for Lines {
     /// Returns `Lines(1)` because when we insert a fresh line into the `ZetSet`
     /// we've seen it once.
@@ -318,17 +852,15 @@ impl Bookkeeping for Lines {
     /// files we've seen.
     fn next_file(&mut self) {}
 
-    /// When `update_with` is called, it means we've seen the line an additional
-    /// time.  We ignore `_other` and just increment our line count (with
-    /// `saturating_add(1)` so we don't wrap around.
-    fn update_with(&mut self, _other: Self) {
-        self.0 = self.0.saturating_add(1);
-    }
-
     /// Our `retention_value` is just the `u32` element.
     fn retention_value(self) -> u32 {
         self.0
     }
+
+    /// The `u32` element is also our line count.
+    fn line_count(self) -> Option<u32> {
+        Some(self.0)
+    }
 }
 impl Loggable for Lines {
     /// Our `log_value` is the same as our `retention_value`: the underlying
@@ -355,10 +887,35 @@ impl Loggable for Lines {
 ///
 /// The `Files` struct has `file_number` and `files_seen` fields.
 #[derive(Clone, Copy, PartialEq, Debug)]
-struct Files {
+pub struct Files {
     file_number: u32,
     files_seen: u32,
 }
+impl Files {
+    /// The state of a line seen in operand `file_number`, to be given with that
+    /// operand. Each operand must have its own `file_number`.
+    #[must_use]
+    pub fn in_operand(file_number: u32) -> Self {
+        Files { file_number, files_seen: 1 }
+    }
+
+    /// The number of operands the line has been seen in
+    #[must_use]
+    pub fn count(self) -> u32 {
+        self.files_seen
+    }
+}
+impl Aggregate for Files {
+    /// If a line is already present in the `ZetSet`, with bookkeeping value
+    /// `b`, and `other.file_number` is different from `b.file_number`, we
+    /// update `b.file_number` and increment `b.files_seen`.
+    fn update_with(&mut self, other: Self) {
+        if other.file_number != self.file_number {
+            self.files_seen += 1;
+            self.file_number = other.file_number;
+        }
+    }
+}
 impl Bookkeeping for Files {
     /// Returns `Files { file_number: 0, files_seen: 1 }` — `file_number` acts
     /// as an ID number, different for each operand, while `files_seen` counts
@@ -374,20 +931,15 @@ impl Bookkeeping for Files {
         self.file_number = self.file_number.wrapping_add(1);
     }
 
-    /// If a line is already present in the `ZetSet`, with bookkeeping value
-    /// `b`, and `other.file_number` is different from `b.file_number`, we
-    /// update `b.file_number` and increment `b.files_seen`.
-    fn update_with(&mut self, other: Self) {
-        if other.file_number != self.file_number {
-            self.files_seen += 1;
-            self.file_number = other.file_number;
-        }
-    }
-
     /// Our `retention_value` is the `files_seen` field.
     fn retention_value(self) -> u32 {
         self.files_seen
     }
+
+    /// The `files_seen` field is also our file count.
+    fn file_count(self) -> Option<u32> {
+        Some(self.files_seen)
+    }
 }
 impl Loggable for Files {
     /// Our `log_value` is the same as our `retention_value` — `files_seen`.
@@ -407,6 +959,17 @@ impl Loggable for Files {
 /// `output_zet_set_annotated`.
 #[derive(Clone, Copy, PartialEq, Debug)]
 struct Log<B: Loggable>(B);
+impl<B: Loggable> Aggregate for Log<B> {
+    fn next_line(&mut self) {
+        self.0.next_line()
+    }
+    fn update_with(&mut self, other: Self) {
+        self.0.update_with(other.0)
+    }
+    fn merge_with(&mut self, other: Self) {
+        self.0.merge_with(other.0)
+    }
+}
 impl<B: Loggable> Bookkeeping for Log<B> {
     fn new() -> Self {
         Self(B::new())
@@ -414,12 +977,15 @@ impl<B: Loggable> Bookkeeping for Log<B> {
     fn next_file(&mut self) {
         self.0.next_file()
     }
-    fn update_with(&mut self, other: Self) {
-        self.0.update_with(other.0)
-    }
     fn retention_value(self) -> u32 {
         self.0.retention_value()
     }
+    fn line_count(self) -> Option<u32> {
+        self.0.line_count()
+    }
+    fn file_count(self) -> Option<u32> {
+        self.0.file_count()
+    }
     fn output_zet_set(set: &ZetSet<Self>, out: impl std::io::Write) -> Result<()> {
         output_zet_set_annotated(set, out)
     }
@@ -443,7 +1009,7 @@ fn output_zet_set_annotated<B: Loggable>(
     let Some(max_count) = set.values().map(|v| v.log_value()).max() else { return Ok(()) };
     let width = (max_count.ilog10() + 1) as usize;
     out.write_all(set.bom)?;
-    for (line, item) in set.iter() {
+    for (line, item) in set.entries() {
         item.write_log(width, &mut out)?;
         out.write_all(line)?;
         out.write_all(set.line_terminator)?;
@@ -452,6 +1018,50 @@ fn output_zet_set_annotated<B: Loggable>(
     Ok(())
 }
 
+/// `output_zet_set_structured` is used instead of `output_zet_set` when the
+/// `Output` calls for structured output or provenance.
+fn output_zet_set_structured<B: Bookkeeping>(
+    set: &ZetSet<B>,
+    out: Sink<impl std::io::Write>,
+) -> Result<()> {
+    write_view(set, set.entries(), out)
+}
+
+/// Write the lines of `view` (some or all of the lines of `set`) with
+/// `write_records`. Structured formats report both counts, while the `Text`
+/// format reports the count (if any) requested by `log_type`.
+fn write_view<'a, B: Bookkeeping + 'a>(
+    set: &ZetSet<B>,
+    view: impl Iterator<Item = (&'a [u8], B)> + Clone,
+    out: Sink<impl std::io::Write>,
+) -> Result<()> {
+    let Sink { log_type, output, out, .. } = out;
+    if let Some(histogram) = &output.histogram {
+        let count = |item: B| match histogram.by {
+            HistogramBy::Lines => item.line_count(),
+            HistogramBy::Files => item.file_count(),
+        };
+        let counts = view.filter_map(|(_, item)| count(item));
+        return histogram.write(counts, set.line_terminator, out);
+    }
+    let (lines_wanted, files_wanted) = match log_type {
+        _ if output.is_structured() => (true, true),
+        CountMode::Lines => (true, false),
+        CountMode::Files => (false, true),
+        CountMode::None => (false, false),
+    };
+    let records = view.map(|(line, item)| {
+        let record = record(line, item);
+        Record {
+            count_lines: record.count_lines.filter(|_| lines_wanted),
+            count_files: record.count_files.filter(|_| files_wanted),
+            provenance: record.provenance.filter(|_| output.wants_provenance()),
+            ..record
+        }
+    });
+    write_records(output, set.bom, set.line_terminator, records, out)
+}
+
 /// A `SiftLog<Sifted, Logged>` struct tracks a `Bookkeeping` item of type
 /// `Sifted` and a `Loggable` item of type `Logged`. The latter will be used to
 /// print a count for each line, either the number of times the line appeared in
@@ -463,6 +1073,27 @@ struct SiftLog<Sifted: Bookkeeping, Logged: Loggable> {
     sift: Sifted,
     log: Logged,
 }
+impl<Sifted: Bookkeeping, Logged: Loggable> Aggregate for SiftLog<Sifted, Logged> {
+    /// Our `next_line` method calls `next_line` for both its fields.
+    fn next_line(&mut self) {
+        self.sift.next_line();
+        self.log.next_line()
+    }
+
+    /// Our `update_with` method calls `update_with` for both its fields,
+    /// sending `other.sift` to our `sift` field and `other.log` to our `log`
+    /// field.
+    fn update_with(&mut self, other: Self) {
+        self.sift.update_with(other.sift);
+        self.log.update_with(other.log);
+    }
+
+    /// Similarly, `merge_with` merges each field separately.
+    fn merge_with(&mut self, other: Self) {
+        self.sift.merge_with(other.sift);
+        self.log.merge_with(other.log);
+    }
+}
 impl<Sifted: Bookkeeping, Logged: Loggable> Bookkeeping for SiftLog<Sifted, Logged> {
     /// Returns `SiftLog { sift: Sifted::new(), log: Logged::new() }` —
     /// freshly inserted lines will have a bookkeeping item suitable for both
@@ -477,19 +1108,21 @@ impl<Sifted: Bookkeeping, Logged: Loggable> Bookkeeping for SiftLog<Sifted, Logg
         self.log.next_file()
     }
 
-    /// Our `update_with` method calls `update_with` for both its fields,
-    /// sending `other.sift` to our `sift` field and `other.log` to our `log`
-    /// field.
-    fn update_with(&mut self, other: Self) {
-        self.sift.update_with(other.sift);
-        self.log.update_with(other.log);
-    }
-
     /// Our `retention_value` is our **`sift` field's** retention value.
     fn retention_value(self) -> u32 {
         self.sift.retention_value()
     }
 
+    /// Our line count comes from whichever field is keeping track of lines.
+    fn line_count(self) -> Option<u32> {
+        self.log.line_count().or(self.sift.line_count())
+    }
+
+    /// Our file count comes from whichever field is keeping track of files.
+    fn file_count(self) -> Option<u32> {
+        self.log.file_count().or(self.sift.file_count())
+    }
+
     /// We override `output_zet_set` to use `output_zet_set_annotated`.
     fn output_zet_set(set: &ZetSet<Self>, out: impl std::io::Write) -> Result<()> {
         output_zet_set_annotated(set, out)
@@ -507,6 +1140,119 @@ impl<Sifted: Bookkeeping, Logged: Loggable> Loggable for SiftLog<Sifted, Logged>
     }
 }
 
+/// A `Tally` keeps track of everything we might want to report about a line:
+/// the number of times it has been seen, the number of files it has been seen
+/// in, and where it was first and last seen. `Tally` isn't itself a
+/// `Bookkeeping` type, since it has no opinion about which lines to retain —
+/// see `Tallied`.
+///
+/// As with `Files`, the `Tally` item passed to `update_with` doubles as a
+/// cursor: its `first` and `last` fields both hold the position of the line
+/// being processed, so a freshly inserted item records where its line first
+/// appeared, and `update_with` can record where a line was last seen. When a
+/// `Base` operand is read first, positions don't arrive in order, so
+/// `update_with` keeps the earliest and latest of them. Likewise, its
+/// `operands` field holds just the operand being processed, and `update_with`
+/// adds that to the line's `operands`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct Tally {
+    lines: Lines,
+    files: Files,
+    first: Provenance,
+    last: Provenance,
+    operands: OperandSet,
+}
+impl Tally {
+    fn new() -> Self {
+        let start = Provenance { file_number: 0, lineno: 0 };
+        let operands = OperandSet::just(0);
+        Tally { lines: Lines::new(), files: Files::new(), first: start, last: start, operands }
+    }
+    fn next_file(&mut self) {
+        self.files.next_file();
+        self.first = Provenance { file_number: self.files.file_number, lineno: 0 };
+        self.last = self.first;
+        self.operands = OperandSet::just(self.files.file_number);
+    }
+    fn next_line(&mut self) {
+        self.first.lineno += 1;
+        self.last.lineno += 1;
+    }
+    fn renumber_file(&mut self, file_number: u32) {
+        self.first.file_number = file_number;
+        self.last.file_number = file_number;
+        self.operands = OperandSet::just(file_number);
+    }
+    fn update_with(&mut self, other: Self) {
+        self.lines.update_with(other.lines);
+        self.files.update_with(other.files);
+        self.first = self.first.min(other.first);
+        self.last = self.last.max(other.last);
+        self.operands.add(other.operands);
+    }
+    fn merge_with(&mut self, other: Self) {
+        self.lines.merge_with(other.lines);
+        self.files.merge_with(other.files);
+        self.first = self.first.min(other.first);
+        self.last = self.last.max(other.last);
+        self.operands.add(other.operands);
+    }
+}
+
+/// A `Tallied<Sifted>` item sifts using its `Sifted` field, exactly as the
+/// `Sifted` type would by itself, and reports counts and provenance from its
+/// `Tally` field. It's used for structured output, where a record carries more
+/// than the single count that `Loggable` provides.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) struct Tallied<Sifted: Bookkeeping> {
+    sift: Sifted,
+    tally: Tally,
+}
+impl<Sifted: Bookkeeping> Aggregate for Tallied<Sifted> {
+    fn next_line(&mut self) {
+        self.sift.next_line();
+        self.tally.next_line()
+    }
+    fn update_with(&mut self, other: Self) {
+        self.sift.update_with(other.sift);
+        self.tally.update_with(other.tally);
+    }
+    fn merge_with(&mut self, other: Self) {
+        self.sift.merge_with(other.sift);
+        self.tally.merge_with(other.tally);
+    }
+}
+impl<Sifted: Bookkeeping> Bookkeeping for Tallied<Sifted> {
+    fn new() -> Self {
+        Tallied { sift: Sifted::new(), tally: Tally::new() }
+    }
+    fn next_file(&mut self) {
+        self.sift.next_file();
+        self.tally.next_file()
+    }
+    fn renumber_file(&mut self, file_number: u32) {
+        self.tally.renumber_file(file_number);
+    }
+    fn retention_value(self) -> u32 {
+        self.sift.retention_value()
+    }
+    fn line_count(self) -> Option<u32> {
+        Some(self.tally.lines.0)
+    }
+    fn file_count(self) -> Option<u32> {
+        Some(self.tally.files.files_seen)
+    }
+    fn provenance(self) -> Option<Provenance> {
+        Some(self.tally.first)
+    }
+    fn last_seen(self) -> Option<Provenance> {
+        Some(self.tally.last)
+    }
+    fn operands(self) -> Option<OperandSet> {
+        Some(self.tally.operands)
+    }
+}
+
 #[allow(clippy::pedantic)]
 #[cfg(test)]
 mod test {
@@ -522,14 +1268,53 @@ mod test {
     }
 
     type V8<'a> = [&'a [u8]];
-    fn calc(operation: OpName, operands: &V8) -> String {
+    fn calc(operation: Operation, operands: &V8) -> String {
         let first = operands[0];
         let rest = operands[1..].iter().map(|o| Ok(*o));
         let mut answer = Vec::new();
-        calculate(operation, LogType::None, first, rest, &mut answer).unwrap();
+        let (output, reading) = (Output::default(), Reading::default());
+        calculate(operation, CountMode::None, &output, &reading, first, rest, &mut answer).unwrap();
         String::from_utf8(answer).unwrap()
     }
 
+    #[test]
+    fn calculate_many_gives_the_same_results_as_calculate() {
+        let operands: Vec<&[u8]> = vec![b"a\nb\nb\nc\n", b"b\nd\nd\n", b"b\ne\nc\n"];
+        let ops = [Intersect, Union, Diff, Single, SingleByFile, Multiple, MultipleByFile];
+        let mut answers = vec![Vec::new(); ops.len()];
+        let operations = ops.iter().copied().zip(answers.iter_mut()).collect();
+        let rest = operands[1..].iter().map(|o| Ok(*o));
+        let (output, reading) = (Output::default(), Reading::default());
+        calculate_many(operations, CountMode::None, &output, &reading, operands[0], rest).unwrap();
+        for (op, answer) in ops.iter().zip(answers) {
+            assert_eq!(String::from_utf8(answer).unwrap(), calc(*op, &operands), "for {op:?}");
+        }
+    }
+
+    #[test]
+    fn reading_in_parallel_gives_the_same_results_as_reading_in_sequence() {
+        let operands: Vec<&[u8]> =
+            vec![b"a\nb\nb\nc\n", b"b\nd\nd\n", b"b\ne\nc\n", b"", b"e\na\nf\n", b"c\nd\n"];
+        let ops = [Intersect, Union, Diff, Single, SingleByFile, Multiple, MultipleByFile];
+        let provenance = Output { provenance: true, ..Output::default() };
+        for output in [Output::default(), provenance] {
+            for log_type in [CountMode::None, CountMode::Lines, CountMode::Files] {
+                for op in ops {
+                    let results = [1, 2, 4].map(|threads| {
+                        let rest = operands[1..].iter().map(|o| Ok(*o));
+                        let mut answer = Vec::new();
+                        let reading = Reading { threads, ..Reading::default() };
+                        calculate(op, log_type, &output, &reading, operands[0], rest, &mut answer)
+                            .unwrap();
+                        String::from_utf8(answer).unwrap()
+                    });
+                    assert_eq!(results[1], results[0], "for {op:?} {log_type:?}");
+                    assert_eq!(results[2], results[0], "for {op:?} {log_type:?}");
+                }
+            }
+        }
+    }
+
     #[test]
     fn given_a_single_argument_all_most_ops_return_input_lines_in_order_without_dups() {
         let arg: Vec<&[u8]> = vec![b"xxx\nabc\nxxx\nyyy\nxxx\nabc\n"];
@@ -567,13 +1352,14 @@ mod test {
         assert_eq!(calc(MultipleByFile, &args), "xyz\nabc\nxy\nxz\nyz\n", "for {MultipleByFile:?}");
     }
 
-    // Test `LogType::Lines` and `LogType::Files' output
+    // Test `CountMode::Lines` and `CountMode::Files' output
     type CountMap = IndexMap<String, u32>;
-    fn counted(operation: OpName, count: LogType, operands: &V8) -> CountMap {
+    fn counted(operation: Operation, count: CountMode, operands: &V8) -> CountMap {
         let first = operands[0];
         let rest = operands[1..].iter().map(|o| Ok(*o));
         let mut answer = Vec::new();
-        calculate(operation, count, first, rest, &mut answer).unwrap();
+        let (output, reading) = (Output::default(), Reading::default());
+        calculate(operation, count, &output, &reading, first, rest, &mut answer).unwrap();
 
         let mut result = CountMap::new();
         for line in String::from_utf8(answer).unwrap().lines() {
@@ -617,7 +1403,7 @@ mod test {
         ];
         let line_count = lines(&args);
         for &op in &[Intersect, Union, Diff, Single, SingleByFile, Multiple, MultipleByFile] {
-            let result = counted(op, LogType::Lines, &args);
+            let result = counted(op, CountMode::Lines, &args);
             for line in result.keys() {
                 assert_eq!(result.get(line), line_count.get(line));
             }
@@ -632,7 +1418,7 @@ mod test {
         ];
         let file_count = files(&args);
         for &op in &[Intersect, Union, Diff, Single, SingleByFile, Multiple, MultipleByFile] {
-            let result = counted(op, LogType::Files, &args);
+            let result = counted(op, CountMode::Files, &args);
             for line in result.keys() {
                 assert_eq!(result.get(line), file_count.get(line));
             }
@@ -660,7 +1446,8 @@ mod test_bookkeeping {
 
     #[test]
     fn log_lines_logs_the_string_overflow_for_u32_max() {
-        let zet = ZetSet::<Log<Lines>>::new(b"a\na\na\nb\n", Log(Lines(u32::MAX - 1)));
+        let item = Log(Lines(u32::MAX - 1));
+        let zet = ZetSet::<Log<Lines>>::new(b"a\na\na\nb\n", item, &mut Budget::new(None));
         let mut result = Vec::new();
         Log::<Lines>::output_zet_set(&zet, &mut result).unwrap();
         let result = String::from_utf8(result).unwrap();
@@ -0,0 +1,114 @@
+//! Provides the `SortOrder` enum, which says what order to output lines in,
+//! the `Sequence` enum, which says how to order lines that `SortOrder`
+//! considers equal, and the `natural_cmp` function used for
+//! `SortOrder::Natural`.
+#[cfg(feature = "cli")]
+use clap::ValueEnum;
+use std::cmp::Ordering;
+
+/// The order in which to output lines. Whatever the order, lines that compare
+/// equal are output in the order given by `Sequence`. (Last-seen order is
+/// `FirstSeen` with `Sequence::LastSeen`: the `zet` command's `--sort
+/// last-seen` is short for `--order last-seen`.)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
+pub enum SortOrder {
+    /// The order in which lines first appear in the input (the default)
+    #[default]
+    FirstSeen,
+    /// Byte by byte, which for UTF-8 is Unicode code point order
+    #[cfg_attr(feature = "cli", value(alias("byte")))]
+    Lexical,
+    /// Like `Lexical`, but with runs of digits compared as numbers, so that
+    /// `v2` sorts before `v10`
+    Natural,
+    /// Most frequent lines first
+    Count,
+    /// Lines in the most files first
+    Files,
+}
+
+/// The underlying sequence of lines: by default, lines are placed where they
+/// first appear in the input. With `LastSeen`, each line is placed where it
+/// last appears, so that "the last one wins". Which lines are output (and with
+/// what counts) is the same either way; only their positions differ.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
+pub enum Sequence {
+    /// Each line is placed at its first appearance (the default)
+    #[default]
+    FirstSeen,
+    /// Each line is placed at its last appearance
+    LastSeen,
+}
+
+/// Compare `a` and `b` in "natural" (or "version") order: runs of ASCII digits
+/// are compared by numeric value, and everything else byte by byte. If two
+/// runs of digits have the same numeric value, the one with fewer leading
+/// zeros comes first.
+#[must_use]
+pub fn natural_cmp(mut a: &[u8], mut b: &[u8]) -> Ordering {
+    loop {
+        match (a.first(), b.first()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let (a_digits, a_rest) = split_digits(a);
+                let (b_digits, b_rest) = split_digits(b);
+                let a_value = trim_zeros(a_digits);
+                let b_value = trim_zeros(b_digits);
+                let ordering = a_value
+                    .len()
+                    .cmp(&b_value.len())
+                    .then_with(|| a_value.cmp(b_value))
+                    .then_with(|| a_digits.len().cmp(&b_digits.len()));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+                (a, b) = (a_rest, b_rest);
+            }
+            (Some(x), Some(y)) => {
+                if x != y {
+                    return x.cmp(y);
+                }
+                (a, b) = (&a[1..], &b[1..]);
+            }
+        }
+    }
+}
+
+/// Split `s` into its leading run of ASCII digits and the rest
+fn split_digits(s: &[u8]) -> (&[u8], &[u8]) {
+    let end = s.iter().position(|b| !b.is_ascii_digit()).unwrap_or(s.len());
+    s.split_at(end)
+}
+
+/// Remove leading zeros from a run of digits
+fn trim_zeros(digits: &[u8]) -> &[u8] {
+    let start = digits.iter().position(|&b| b != b'0').unwrap_or(digits.len());
+    &digits[start..]
+}
+
+#[allow(clippy::pedantic)]
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn natural_order_compares_digit_runs_numerically() {
+        let mut lines: Vec<&[u8]> =
+            vec![b"v10", b"v2", b"v1.10", b"v1.9", b"v", b"v02", b"w1", b"v1.9a"];
+        lines.sort_by(|a, b| natural_cmp(a, b));
+        let expected: Vec<&[u8]> =
+            vec![b"v", b"v1.9", b"v1.9a", b"v1.10", b"v2", b"v02", b"v10", b"w1"];
+        assert_eq!(lines, expected);
+    }
+
+    #[test]
+    fn natural_order_is_equal_only_for_equal_lines() {
+        assert_eq!(natural_cmp(b"a01b", b"a01b"), Ordering::Equal);
+        assert_eq!(natural_cmp(b"a1b", b"a01b"), Ordering::Less);
+        assert_eq!(natural_cmp(b"", b""), Ordering::Equal);
+    }
+}
@@ -0,0 +1,631 @@
+//! Provides the `Output` structure, which tells `calculate` how to format its
+//! result, and the `write_records` function, which writes the result in the
+//! requested format.
+//!
+//! The default `Text` format is usually written by `Bookkeeping::output_zet_set`
+//! without going through this module at all. We use `write_records` when the
+//! caller asks for something the `Bookkeeping` fast path can't provide: a
+//! structured format (`Json`, `Ndjson`, `Tsv`, or `Csv`), provenance
+//! information, or counts formatted other than the default way.
+use crate::histogram::Histogram;
+use crate::order::{Sequence, SortOrder};
+use crate::styles::StyleSheet;
+use crate::template::Template;
+use anyhow::{bail, Result};
+use bstr::ByteSlice;
+#[cfg(feature = "cli")]
+use clap::ValueEnum;
+use std::borrow::Cow;
+use std::io;
+
+/// The format of `zet`'s output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
+pub enum OutputFormat {
+    /// Lines as found in the input, optionally preceded by a right-aligned count
+    #[default]
+    Text,
+    /// A single JSON array with one object per output line
+    Json,
+    /// Newline-delimited JSON: one JSON object per output line
+    Ndjson,
+    /// Tab-separated values, with a header row
+    Tsv,
+    /// Comma-separated values, with a header row
+    Csv,
+}
+
+/// Where to put the count relative to the line
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
+pub enum CountPosition {
+    /// Before the line (the default)
+    #[default]
+    Prefix,
+    /// After the line
+    Suffix,
+}
+
+/// How to report a line count that has saturated at `u32::MAX`, so that the
+/// true count may be larger
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
+pub enum Saturated {
+    /// Write the word `overflow` (the default). JSON output writes
+    /// `4294967295`, and marks the count as saturated with a separate field.
+    #[default]
+    Word,
+    /// Write `u32::MAX`, `4294967295`
+    Max,
+    /// Stop with an error before writing any output
+    Error,
+}
+
+/// Keep only the `n` lines with the highest (`Top`) or lowest (`Bottom`)
+/// count
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Selection {
+    Top(usize),
+    Bottom(usize),
+}
+
+/// How to format the result of `calculate`
+#[derive(Debug, Clone)]
+pub struct Output {
+    /// The output format
+    pub format: OutputFormat,
+    /// Should we report the file and line number where each line first appeared?
+    pub provenance: bool,
+    /// The names of the operands, used to report provenance. If `provenance` is
+    /// `true` there should be one name per operand, in command line order.
+    pub operand_names: Vec<String>,
+    /// Does the count go before or after the line? Used by the `Text`, `Tsv`,
+    /// and `Csv` formats.
+    pub count_position: CountPosition,
+    /// The `Text` format's separator between the count and the line. Counts
+    /// are right-aligned only when the separator is the default, a single
+    /// space.
+    pub count_separator: String,
+    /// How to report saturated line counts
+    pub saturated: Saturated,
+    /// The order in which to output lines
+    pub sort: SortOrder,
+    /// The sequence used when `sort` considers lines equal (and when `sort`
+    /// is `SortOrder::FirstSeen`, the output order)
+    pub order: Sequence,
+    /// Reverse the output order?
+    pub reverse: bool,
+    /// Keep only the most (or least) frequent lines?
+    pub select: Option<Selection>,
+    /// Does `select` rank lines by the number of files they appear in, rather
+    /// than the number of times they appear?
+    pub select_by_files: bool,
+    /// How to color the `Text` format's counts, provenance labels, and
+    /// separators. The default is plain text.
+    pub style: StyleSheet,
+    /// A `--format` template to write each line with, in place of the `Text`
+    /// format's usual layout
+    pub template: Option<Template>,
+    /// Report the distribution of counts with a histogram, rather than the
+    /// lines themselves?
+    pub histogram: Option<Histogram>,
+}
+
+impl Default for Output {
+    fn default() -> Self {
+        Output {
+            format: OutputFormat::default(),
+            provenance: false,
+            operand_names: Vec::new(),
+            count_position: CountPosition::default(),
+            count_separator: DEFAULT_SEPARATOR.to_string(),
+            saturated: Saturated::default(),
+            sort: SortOrder::default(),
+            order: Sequence::default(),
+            reverse: false,
+            select: None,
+            select_by_files: false,
+            style: StyleSheet::default(),
+            template: None,
+            histogram: None,
+        }
+    }
+}
+const DEFAULT_SEPARATOR: &str = " ";
+
+impl Output {
+    /// Does this output need information that only the `Tally` bookkeeping
+    /// type keeps, or formatting that only `write_records` does? (If so, only
+    /// `calculate` can write it.)
+    #[must_use]
+    pub fn needs_tally(&self) -> bool {
+        self.format != OutputFormat::Text
+            || self.provenance
+            || self.count_position != CountPosition::Prefix
+            || self.count_separator != DEFAULT_SEPARATOR
+            || self.saturated != Saturated::Word
+            || matches!(self.sort, SortOrder::Count | SortOrder::Files)
+            || self.order == Sequence::LastSeen
+            || self.select.is_some()
+            || self.style.is_colored()
+            || self.template.is_some()
+            || self.histogram.is_some()
+    }
+
+    /// Does this output put the lines in some order other than that of their
+    /// first appearance? (If so, only `calculate` can write it.)
+    #[must_use]
+    pub fn reorders(&self) -> bool {
+        self.sort != SortOrder::FirstSeen || self.order != Sequence::FirstSeen || self.reverse
+    }
+
+    /// Structured formats always report both the line count and the file
+    /// count, and so do templates, which may use either.
+    pub(crate) fn is_structured(&self) -> bool {
+        self.format != OutputFormat::Text || self.template.is_some()
+    }
+
+    /// Do we need to know where each line first appeared?
+    pub(crate) fn wants_provenance(&self) -> bool {
+        self.provenance || self.template.as_ref().is_some_and(Template::uses_provenance)
+    }
+
+    /// Bail if the output needs the `OperandSet` of each line, and there are
+    /// more `operands` than it can hold
+    pub(crate) fn check_operands(&self, operands: usize) -> Result<()> {
+        if operands > OperandSet::MAX && self.template.as_ref().is_some_and(Template::uses_files) {
+            bail!("A template's {{files}} works with at most {} operands", OperandSet::MAX)
+        }
+        Ok(())
+    }
+
+    /// The name of the operand numbered `file_number` (counting from `0`)
+    pub(crate) fn operand_name(&self, file_number: u32) -> &str {
+        self.operand_names.get(file_number as usize).map_or("<unknown>", String::as_str)
+    }
+
+    /// The text of a line count, taking saturation into account
+    pub(crate) fn line_count_text(&self, count: u32) -> Cow<'static, str> {
+        if count == u32::MAX && self.saturated == Saturated::Word {
+            Cow::Borrowed("overflow")
+        } else {
+            Cow::Owned(count.to_string())
+        }
+    }
+
+    /// The text of the single count reported by the `Text` format: the line
+    /// count if there is one, otherwise the file count.
+    fn count_text(&self, record: &Record) -> Option<Cow<'static, str>> {
+        match (record.count_lines, record.count_files) {
+            (Some(count), _) => Some(self.line_count_text(count)),
+            (None, Some(count)) => Some(Cow::Owned(count.to_string())),
+            (None, None) => None,
+        }
+    }
+}
+
+/// An output line, together with whatever counts and provenance information
+/// was asked for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Record<'a> {
+    /// The line itself, without its line terminator
+    pub line: &'a [u8],
+    /// The number of times the line appears in the input
+    pub count_lines: Option<u32>,
+    /// The number of files the line appears in
+    pub count_files: Option<u32>,
+    /// Where the line first appeared
+    pub provenance: Option<Provenance>,
+    /// The operands the line appears in
+    pub operands: Option<OperandSet>,
+}
+
+/// A position in the input: the operand number (counting from `0`) and line
+/// number (counting from `1`). Used to report a line's first appearance in the
+/// input. Positions are ordered as they are encountered in the input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Provenance {
+    pub file_number: u32,
+    pub lineno: u64,
+}
+
+/// A set of operands, numbered as for `Provenance`. Only operands `0` to
+/// `OperandSet::MAX - 1` can be members; `Output::check_operands` makes sure
+/// there are no others when the set is needed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct OperandSet(u64);
+impl OperandSet {
+    /// The number of operands an `OperandSet` can hold
+    pub const MAX: usize = 64;
+
+    /// The set holding just the operand numbered `file_number`
+    #[must_use]
+    pub fn just(file_number: u32) -> Self {
+        OperandSet(1_u64.checked_shl(file_number).unwrap_or(0))
+    }
+
+    /// Add the members of `other` to this set
+    pub fn add(&mut self, other: Self) {
+        self.0 |= other.0;
+    }
+
+    /// The numbers of the operands in the set, in increasing order
+    pub fn iter(self) -> impl Iterator<Item = u32> {
+        (0..u64::BITS).filter(move |&n| self.0 & (1 << n) != 0)
+    }
+}
+
+/// Write `records` in the format specified by `output`. For the `Text`,
+/// `Tsv`, and `Csv` formats, we begin with `bom` and end each line with
+/// `line_terminator`, just as `Bookkeeping::output_zet_set` does. The JSON
+/// formats ignore both: JSON is always UTF-8 without a byte order mark, and
+/// NDJSON records always end in `\n`.
+pub(crate) fn write_records<'a>(
+    output: &Output,
+    bom: &[u8],
+    line_terminator: &[u8],
+    records: impl Iterator<Item = Record<'a>> + Clone,
+    mut out: impl io::Write,
+) -> Result<()> {
+    if output.saturated == Saturated::Error {
+        if let Some(r) = records.clone().find(|r| r.count_lines == Some(u32::MAX)) {
+            bail!("The line count of \"{}\" is too large to report exactly", r.line.as_bstr())
+        }
+    }
+    match output.format {
+        OutputFormat::Text if output.template.is_some() => {
+            let template = output.template.as_ref().expect("checked by the match guard");
+            out.write_all(bom)?;
+            for record in records {
+                template.render(output, &record, &mut out)?;
+                out.write_all(line_terminator)?;
+            }
+        }
+        OutputFormat::Text => {
+            let padded = output.count_position == CountPosition::Prefix
+                && output.count_separator == DEFAULT_SEPARATOR;
+            let width = if padded {
+                records.clone().filter_map(|r| output.count_text(&r)).map(|c| c.len()).max()
+            } else {
+                None
+            };
+            out.write_all(bom)?;
+            for record in records {
+                write_text(output, width.unwrap_or(0), &record, &mut out)?;
+                out.write_all(line_terminator)?;
+            }
+        }
+        OutputFormat::Json => {
+            let mut separator: &[u8] = b"[\n";
+            for record in records {
+                out.write_all(separator)?;
+                write_json(output, &record, &mut out)?;
+                separator = b",\n";
+            }
+            out.write_all(if separator == b"[\n" { b"[]\n" } else { b"\n]\n" })?;
+        }
+        OutputFormat::Ndjson => {
+            for record in records {
+                write_json(output, &record, &mut out)?;
+                out.write_all(b"\n")?;
+            }
+        }
+        OutputFormat::Tsv | OutputFormat::Csv => {
+            out.write_all(bom)?;
+            let header = columns(output).map(|c| Cow::Borrowed(c.name().as_bytes()));
+            write_delimited(output, header, &mut out)?;
+            out.write_all(line_terminator)?;
+            for record in records {
+                let fields = columns(output).map(|c| c.field(output, &record));
+                write_delimited(output, fields, &mut out)?;
+                out.write_all(line_terminator)?;
+            }
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Write a `Text` record: the count (if any), then the provenance (if any) as
+/// `file:lineno:`, then the line itself — or, with `CountPosition::Suffix`, the
+/// provenance, the line, and then the count. A count `width` of zero means no
+/// padding.
+fn write_text(
+    output: &Output,
+    width: usize,
+    record: &Record,
+    out: &mut impl io::Write,
+) -> Result<()> {
+    let style = &output.style;
+    let count = output.count_text(record);
+    let separator = style.separator(&output.count_separator);
+    if let (Some(count), CountPosition::Prefix) = (&count, output.count_position) {
+        let padding = width.saturating_sub(count.len());
+        write!(out, "{:padding$}{}{separator}", "", style.count(count))?
+    }
+    if let Some(p) = record.provenance {
+        let name = style.file_name(output.operand_name(p.file_number));
+        let lineno = p.lineno.to_string();
+        let colon = style.separator(":");
+        write!(out, "{name}{colon}{}{colon}", style.lineno(&lineno))?
+    }
+    out.write_all(record.line)?;
+    if let (Some(count), CountPosition::Suffix) = (&count, output.count_position) {
+        write!(out, "{separator}{}", style.count(count))?
+    }
+    Ok(())
+}
+
+/// The columns of `Tsv` and `Csv` output
+#[derive(Clone, Copy)]
+enum Column {
+    Line,
+    CountLines,
+    CountFiles,
+    FirstFile,
+    FirstLineno,
+}
+impl Column {
+    /// The column name, for the header row. These are the same as the field
+    /// names used in JSON output.
+    fn name(self) -> &'static str {
+        match self {
+            Column::Line => "line",
+            Column::CountLines => "count_lines",
+            Column::CountFiles => "count_files",
+            Column::FirstFile => "first_file",
+            Column::FirstLineno => "first_lineno",
+        }
+    }
+
+    /// The (unescaped) contents of this column for `record`
+    fn field<'r>(self, output: &'r Output, record: &Record<'r>) -> Cow<'r, [u8]> {
+        let number = |n: Option<u64>| -> Cow<'r, [u8]> {
+            Cow::Owned(n.map_or_else(Vec::new, |n| n.to_string().into_bytes()))
+        };
+        match self {
+            Column::Line => Cow::Borrowed(record.line),
+            Column::CountLines => match record.count_lines {
+                Some(count) => Cow::Owned(output.line_count_text(count).into_owned().into_bytes()),
+                None => Cow::Borrowed(b""),
+            },
+            Column::CountFiles => number(record.count_files.map(u64::from)),
+            Column::FirstFile => match record.provenance {
+                Some(p) => Cow::Borrowed(output.operand_name(p.file_number).as_bytes()),
+                None => Cow::Borrowed(b""),
+            },
+            Column::FirstLineno => number(record.provenance.map(|p| p.lineno)),
+        }
+    }
+}
+
+/// The columns to write, in order: the counts go before or after the line
+/// according to `output.count_position`, and the provenance columns (if any)
+/// go last.
+fn columns(output: &Output) -> impl Iterator<Item = Column> {
+    use Column::{CountFiles, CountLines, FirstFile, FirstLineno, Line};
+    let columns: &[Column] = match output.count_position {
+        CountPosition::Prefix => &[CountLines, CountFiles, Line],
+        CountPosition::Suffix => &[Line, CountLines, CountFiles],
+    };
+    let provenance: &[Column] = if output.provenance { &[FirstFile, FirstLineno] } else { &[] };
+    columns.iter().chain(provenance).copied()
+}
+
+/// Write one row of `Tsv` or `Csv` output. `Tsv` fields escape tabs, carriage
+/// returns, and backslashes with a backslash; `Csv` fields are quoted (as
+/// described in RFC 4180) when they contain a comma, a double quote, or a
+/// carriage return.
+fn write_delimited<'f>(
+    output: &Output,
+    fields: impl Iterator<Item = Cow<'f, [u8]>>,
+    out: &mut impl io::Write,
+) -> Result<()> {
+    let csv = output.format == OutputFormat::Csv;
+    for (n, field) in fields.enumerate() {
+        if n > 0 {
+            out.write_all(if csv { b"," } else { b"\t" })?;
+        }
+        if csv {
+            if field.find_byteset(b",\"\r\n").is_some() {
+                out.write_all(b"\"")?;
+                out.write_all(&field.replace("\"", "\"\""))?;
+                out.write_all(b"\"")?;
+            } else {
+                out.write_all(&field)?;
+            }
+        } else {
+            let mut unescaped = 0;
+            for (i, b) in field.iter().enumerate() {
+                let escape: &[u8] = match b {
+                    b'\t' => b"\\t",
+                    b'\r' => b"\\r",
+                    b'\\' => b"\\\\",
+                    _ => continue,
+                };
+                out.write_all(&field[unescaped..i])?;
+                out.write_all(escape)?;
+                unescaped = i + 1;
+            }
+            out.write_all(&field[unescaped..])?;
+        }
+    }
+    Ok(())
+}
+
+/// Write a record as a single-line JSON object. A line that is valid UTF-8 is
+/// written as the string field `line`; otherwise we write the base64 encoding
+/// of its bytes as the field `line_base64`. The `count_lines` field is always
+/// a number: where `Text` would write `overflow`, we write `u32::MAX` and add
+/// the field `"count_lines_saturated": true`.
+fn write_json(output: &Output, record: &Record, out: &mut impl io::Write) -> Result<()> {
+    if let Ok(line) = std::str::from_utf8(record.line) {
+        out.write_all(b"{\"line\":")?;
+        write_json_string(line, out)?;
+    } else {
+        out.write_all(b"{\"line_base64\":\"")?;
+        out.write_all(base64(record.line).as_bytes())?;
+        out.write_all(b"\"")?;
+    }
+    if let Some(count) = record.count_lines {
+        write!(out, ",\"count_lines\":{count}")?;
+        if count == u32::MAX && output.saturated == Saturated::Word {
+            out.write_all(b",\"count_lines_saturated\":true")?
+        }
+    }
+    if let Some(count) = record.count_files {
+        write!(out, ",\"count_files\":{count}")?
+    }
+    if let Some(p) = record.provenance {
+        out.write_all(b",\"first_file\":")?;
+        write_json_string(output.operand_name(p.file_number), out)?;
+        write!(out, ",\"first_lineno\":{}", p.lineno)?
+    }
+    out.write_all(b"}")?;
+    Ok(())
+}
+
+/// Write `s` as a JSON string literal, escaping quotes, backslashes, and
+/// control characters.
+fn write_json_string(s: &str, out: &mut impl io::Write) -> Result<()> {
+    out.write_all(b"\"")?;
+    let mut unescaped = 0;
+    for (i, b) in s.bytes().enumerate() {
+        let escape: &[u8] = match b {
+            b'"' => b"\\\"",
+            b'\\' => b"\\\\",
+            b'\n' => b"\\n",
+            b'\r' => b"\\r",
+            b'\t' => b"\\t",
+            0..=0x1f => b"",
+            _ => continue,
+        };
+        out.write_all(&s.as_bytes()[unescaped..i])?;
+        if escape.is_empty() {
+            write!(out, "\\u{b:04x}")?
+        } else {
+            out.write_all(escape)?
+        }
+        unescaped = i + 1;
+    }
+    out.write_all(&s.as_bytes()[unescaped..])?;
+    out.write_all(b"\"")?;
+    Ok(())
+}
+
+/// The standard (RFC 4648) base64 encoding of `bytes`, with padding.
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut result = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = u32::from(b[0]) << 16 | u32::from(b[1]) << 8 | u32::from(b[2]);
+        for (i, shift) in [18, 12, 6, 0].into_iter().enumerate() {
+            if i <= chunk.len() {
+                result.push(char::from(ALPHABET[(n >> shift) as usize & 0x3f]));
+            } else {
+                result.push('=');
+            }
+        }
+    }
+    result
+}
+
+#[allow(clippy::pedantic)]
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn base64_matches_rfc_4648_test_vectors() {
+        let vectors =
+            [("", ""), ("f", "Zg=="), ("fo", "Zm8="), ("foo", "Zm9v"), ("foob", "Zm9vYg==")];
+        for (plain, encoded) in vectors {
+            assert_eq!(base64(plain.as_bytes()), encoded, "for {plain:?}");
+        }
+        assert_eq!(base64(b"fooba"), "Zm9vYmE=");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+    }
+
+    #[test]
+    fn json_strings_escape_quotes_backslashes_and_control_characters() {
+        let mut result = Vec::new();
+        write_json_string("a\"b\\c\td\u{1}é", &mut result).unwrap();
+        assert_eq!(String::from_utf8(result).unwrap(), r#""a\"b\\c\td\u0001é""#);
+    }
+
+    #[test]
+    fn non_utf8_lines_are_written_as_base64() {
+        let output = Output { format: OutputFormat::Ndjson, ..Output::default() };
+        let record = Record {
+            line: b"\xff\xfe",
+            count_lines: Some(2),
+            count_files: None,
+            ..Record::default()
+        };
+        let mut result = Vec::new();
+        write_records(&output, b"", b"\n", [record].into_iter(), &mut result).unwrap();
+        assert_eq!(
+            String::from_utf8(result).unwrap(),
+            "{\"line_base64\":\"//4=\",\"count_lines\":2}\n"
+        );
+    }
+
+    fn written(output: &Output, records: &[Record]) -> String {
+        let mut result = Vec::new();
+        write_records(output, b"", b"\n", records.iter().copied(), &mut result).unwrap();
+        String::from_utf8(result).unwrap()
+    }
+    fn counted(line: &[u8], count: u32) -> Record<'_> {
+        Record { line, count_lines: Some(count), count_files: Some(1), ..Record::default() }
+    }
+
+    #[test]
+    fn saturated_counts_are_reported_as_requested() {
+        let records = [counted(b"a", u32::MAX), counted(b"b", 7)];
+        let mut output = Output { count_position: CountPosition::Suffix, ..Output::default() };
+        assert_eq!(written(&output, &records), "a overflow\nb 7\n");
+        output.saturated = Saturated::Max;
+        assert_eq!(written(&output, &records), "a 4294967295\nb 7\n");
+        output.saturated = Saturated::Error;
+        let mut result = Vec::new();
+        assert!(write_records(&output, b"", b"\n", records.into_iter(), &mut result).is_err());
+        assert!(result.is_empty());
+    }
+
+    #[test]
+    fn saturated_json_counts_are_numbers_with_a_flag() {
+        let records = [counted(b"a", u32::MAX), counted(b"b", 7)];
+        let mut output = Output { format: OutputFormat::Ndjson, ..Output::default() };
+        assert_eq!(
+            written(&output, &records),
+            "{\"line\":\"a\",\"count_lines\":4294967295,\"count_lines_saturated\":true,\"count_files\":1}\n\
+             {\"line\":\"b\",\"count_lines\":7,\"count_files\":1}\n"
+        );
+        output.saturated = Saturated::Max;
+        assert!(!written(&output, &records).contains("saturated"));
+    }
+
+    #[test]
+    fn counts_are_padded_only_with_the_default_separator() {
+        let records = [counted(b"a", 10), counted(b"b", 7)];
+        assert_eq!(written(&Output::default(), &records), "10 a\n 7 b\n");
+        let output = Output { count_separator: "\t".to_string(), ..Output::default() };
+        assert_eq!(written(&output, &records), "10\ta\n7\tb\n");
+    }
+
+    #[test]
+    fn csv_fields_are_quoted_and_tsv_fields_are_escaped() {
+        let records = [counted(b"a,\"b\"", 1), counted(b"c\td\\", 2)];
+        let output = Output { format: OutputFormat::Csv, ..Output::default() };
+        assert_eq!(
+            written(&output, &records),
+            "count_lines,count_files,line\n1,1,\"a,\"\"b\"\"\"\n2,1,c\td\\\n"
+        );
+        let output = Output { format: OutputFormat::Tsv, ..Output::default() };
+        assert_eq!(
+            written(&output, &records),
+            "count_lines\tcount_files\tline\n1\t1\ta,\"b\"\n2\t1\tc\\td\\\\\n"
+        );
+    }
+}
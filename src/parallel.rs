@@ -0,0 +1,93 @@
+//! Provides the `read_in_parallel` function, used by `calculate` to read the
+//! second and later operands with several threads.
+use crate::operations::Bookkeeping;
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::sync::{mpsc, Condvar, Mutex, MutexGuard};
+use std::thread;
+
+/// The operands waiting to be read, shared by the reading threads
+struct Queue<I, B> {
+    /// The operands not yet taken by a reading thread
+    operands: I,
+    /// The bookkeeping item for the operand most recently taken
+    item: B,
+    /// The number of operands taken so far
+    taken: usize,
+    /// The number of operands whose results have been merged
+    merged: usize,
+    /// Set if merging has failed, to tell the reading threads to stop
+    stop: bool,
+}
+
+/// Read `operands` with `threads` threads. Each thread takes the next operand
+/// and calls `read(operand, item)`, where `item` is the bookkeeping item for
+/// that operand — as if `item.next_file()` had been called for each operand in
+/// turn. We pass the results to `merge` strictly in operand order, so merging
+/// them gives the same result as reading the operands one at a time would.
+///
+/// Threads don't take an operand more than `2 * threads` operands ahead of the
+/// next one to be merged, so at most that many results wait to be merged.
+///
+/// If taking or reading an operand fails, we return the error for the first
+/// operand (in operand order) that fails, after merging the results of the
+/// operands before it.
+pub(crate) fn read_in_parallel<O, B: Bookkeeping, P: Send>(
+    operands: impl Iterator<Item = Result<O>> + Send,
+    item: B,
+    threads: usize,
+    read: impl Fn(O, B) -> Result<P> + Sync,
+    mut merge: impl FnMut(P),
+) -> Result<()> {
+    let window = 2 * threads;
+    let queue = Mutex::new(Queue { operands, item, taken: 0, merged: 0, stop: false });
+    let ready = Condvar::new();
+    let lock = || queue.lock().unwrap_or_else(std::sync::PoisonError::into_inner);
+    let (sender, receiver) = mpsc::channel::<(usize, Result<P>)>();
+
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            let sender = sender.clone();
+            let (lock, ready, read) = (&lock, &ready, &read);
+            scope.spawn(move || loop {
+                let mut queue: MutexGuard<_> = lock();
+                while !queue.stop && queue.taken >= queue.merged + window {
+                    queue = ready.wait(queue).unwrap_or_else(std::sync::PoisonError::into_inner);
+                }
+                if queue.stop {
+                    return;
+                }
+                let Some(operand) = queue.operands.next() else { return };
+                queue.item.next_file();
+                let (index, item) = (queue.taken, queue.item);
+                queue.taken += 1;
+                drop(queue);
+
+                let result = operand.and_then(|operand| read(operand, item));
+                if sender.send((index, result)).is_err() {
+                    return;
+                }
+            });
+        }
+        drop(sender);
+
+        let mut waiting = BTreeMap::new();
+        let mut next = 0;
+        for (index, result) in receiver {
+            waiting.insert(index, result);
+            while let Some(result) = waiting.remove(&next) {
+                let merged = result.map(&mut merge);
+                let mut queue = lock();
+                if merged.is_err() {
+                    queue.stop = true;
+                }
+                next += 1;
+                queue.merged = next;
+                drop(queue);
+                ready.notify_all();
+                merged?;
+            }
+        }
+        Ok(())
+    })
+}
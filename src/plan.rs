@@ -0,0 +1,78 @@
+//! Provides `plan`, which decides which operand to read first. `calculate`
+//! builds its `ZetSet` from the operand it reads first, so for `Intersect`,
+//! whose result can only contain lines of every operand, reading the smallest
+//! operand first keeps the set small and makes the later operands' lookups
+//! cheap. The `--base N` flag picks the operand to read first for any
+//! operation but `Diff`, whose result can only contain lines of the first
+//! operand.
+//!
+//! Either way, the result is the same as reading the operands in command-line
+//! order: `calculate` puts the lines back in the order they first appeared
+//! there, and writes them with the first operand's byte order mark and line
+//! terminator. The exception is a `.zidx` index (see `index`) as the first
+//! operand of `intersect`: we can't read its lines, so we read another operand
+//! first, and the result follows that operand instead.
+use crate::index::is_index;
+use crate::operands::{output_info_for, use_stdin};
+use crate::operations::Base;
+use crate::operations::Operation::{self, Intersect};
+use anyhow::{bail, Result};
+use std::fs;
+use std::path::PathBuf;
+
+/// Returns the `Base` operand to read first, or `None` to read the first
+/// operand first. `requested` is the position given with `--base`, counting
+/// from `1`. Without it, we choose the smallest operand for `Intersect`.
+///
+/// # Errors
+///
+/// Returns an error if `requested` is past the last operand, or if the first
+/// operand is standard input, whose byte order mark and line terminator we
+/// can't see without reading it first. (Standard input is never chosen
+/// automatically.)
+pub fn plan(
+    operation: Operation,
+    paths: &[PathBuf],
+    requested: Option<usize>,
+) -> Result<Option<Base>> {
+    let operands = paths.len().max(1); // No paths means standard input
+    let first_text = || paths.iter().position(|path| !is_index(path));
+    let position = match requested {
+        Some(n) if n > operands => bail!("--base {n}: there are only {operands} operand(s)"),
+        Some(n) => n - 1,
+        None if operation == Intersect => smallest(paths).or_else(first_text).unwrap_or(0),
+        None => 0,
+    };
+    if position == 0 {
+        return Ok(None);
+    }
+    let first = &paths[0];
+    if use_stdin(first) {
+        bail!(
+            "Can't read --base operand {} first when the first operand is standard input",
+            position + 1
+        )
+    }
+    // An index has no lines to put in order, or take a line terminator
+    // from, so then we go by the base operand
+    let (bom, line_terminator) =
+        output_info_for(if is_index(first) { &paths[position] } else { first })?;
+    Ok(Some(Base { position, bom, line_terminator }))
+}
+
+/// The position of the smallest operand that isn't an index, counting from
+/// `0`, or `None` unless every operand is a regular file whose size we can
+/// learn without reading it. Ties go to the earliest operand.
+fn smallest(paths: &[PathBuf]) -> Option<usize> {
+    let mut sizes = Vec::with_capacity(paths.len());
+    for (position, path) in paths.iter().enumerate() {
+        if use_stdin(path) {
+            return None;
+        }
+        if !is_index(path) {
+            let metadata = fs::metadata(path).ok().filter(fs::Metadata::is_file)?;
+            sizes.push((position, metadata.len()));
+        }
+    }
+    sizes.iter().min_by_key(|&(_, size)| size).map(|&(position, _)| position)
+}
@@ -1,16 +1,26 @@
 //! Provides the `ZetSet` structure, intended to be initialized from the
 //! contents of the first input file.
+use crate::budget::Budget;
+use crate::index::SetIndex;
 use crate::operations::Bookkeeping;
 use anyhow::Result;
-use fxhash::FxBuildHasher;
-use indexmap::{map, IndexMap};
+use fxhash::{FxBuildHasher, FxHashMap};
+use indexmap::map::{self, raw_entry_v1::RawEntryMut, RawEntryApiV1};
+use indexmap::IndexMap;
 use memchr::memchr;
 use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::hash::BuildHasher;
+use std::mem::size_of;
+use std::ops::ControlFlow;
 
 /// A `ZetSet` is a set of lines, each line represented as a key of an `IndexMap`.
 /// * Keys are `Cow<'data, [u8]>`
 /// * Lines inserted from the first file operand are represented as `Cow::Borrowed` keys
-/// * Lines inserted from the second and following files are represented as `Cow::Owned` keys
+/// * Lines inserted from the second and following files are copied into an `Arena`, an
+///   append-only store of bytes shared by all those lines, and represented as
+///   `Cow::Borrowed` keys into the arena. (A set with no arena — one returned by
+///   `from_operand` — represents them as `Cow::Owned` keys instead.)
 /// * Each set operation (`Union`, `Diff`, etc) associates a small bookkeeping value
 ///   with each key. The value type differs from operation to operation, and by whether we're
 ///   counting the number of times each line appears, or the number of files in which each
@@ -21,13 +31,22 @@ use std::borrow::Cow;
 ///   file operand had one, and will use the same line terminator as that file's first
 ///   line.
 #[derive(Clone, Debug)]
-pub(crate) struct ZetSet<'data, B: Bookkeeping> {
+pub(crate) struct ZetSet<'data, B: Aggregate> {
     set: CowSet<'data, B>,
     pub(crate) bom: &'static [u8], // Byte Order Mark or empty
     pub(crate) line_terminator: &'static [u8], // \n or \r\n
 }
 type CowSet<'data, B> = IndexMap<Cow<'data, [u8]>, B, FxBuildHasher>;
 
+/// The store for lines inserted from the second and following operands. Copying
+/// each new line into one large chunk of memory is much cheaper than allocating
+/// a `Vec<u8>` for each line.
+pub(crate) type Arena = bumpalo::Bump;
+
+/// The bookkeeping updates from one operand for the lines of a `ZetSet`,
+/// keyed by each line's index in the set. Returned by `updates_if_present`.
+pub(crate) type IndexUpdates<B> = FxHashMap<usize, B>;
+
 /// We don't, in fact, require the second and following "files" to be files! Our
 /// only requirement is that they implement `for_byte_line`. The `LaterOperand`
 /// trait codifies that.
@@ -35,6 +54,66 @@ pub trait LaterOperand {
     /// The call `o.for_byte_line(|line| ...)` method calls the given closure
     /// for each &[u8] in `o`.
     fn for_byte_line(self, for_each_line: impl FnMut(&[u8])) -> Result<()>;
+
+    /// Like `for_byte_line`, but stop (without error) once the closure returns
+    /// `ControlFlow::Break`. The provided implementation can't stop reading,
+    /// so it reads all of `o`, skipping the lines after the break; operands
+    /// that can stop should override it.
+    fn try_for_byte_line(
+        self,
+        mut for_each_line: impl FnMut(&[u8]) -> ControlFlow<()>,
+    ) -> Result<()>
+    where
+        Self: Sized,
+    {
+        let mut stopped = false;
+        self.for_byte_line(|line| {
+            if !stopped {
+                stopped = for_each_line(line).is_break();
+            }
+        })
+    }
+
+    /// If the operand is a `.zidx` index, whose lines we can't read but can
+    /// look up, return it. The provided implementation returns `None`.
+    ///
+    /// This method isn't part of the stable interface that `builder`
+    /// re-exports this trait in: only `zet`'s own operands override it.
+    #[doc(hidden)]
+    fn index(&self) -> Option<&SetIndex> {
+        None
+    }
+}
+
+/// The state a `ZetSet` keeps for each of its lines. Each operand comes with
+/// a value of the `Aggregate` type, which is copied for each of its lines: a
+/// line seen for the first time gets the copy as its state, and a line seen
+/// again, with state `v`, is updated by `v.update_with(copy)`.
+///
+/// The `zet` command's bookkeeping types (`Unsifted`, `Lines`, and `Files` —
+/// re-exported by `builder` as `Unsifted`, `LineCount`, and `FileCount` — and
+/// the types built from them) are `Aggregate`s that count a line's
+/// appearances; library users can implement `Aggregate` to keep other state —
+/// the latest timestamp of the operands a line appears in, say — and use it
+/// with `builder::Aggregation`.
+pub trait Aggregate: Copy {
+    /// Update the state of a line seen again, where `other` is the value for
+    /// the operand it's seen in
+    fn update_with(&mut self, other: Self);
+
+    /// Here `other` is the state a line got from a later operand read by
+    /// itself (see `read_in_parallel`). Merge it into this state, as if
+    /// `update_with` had been called for each of the line's appearances in
+    /// that operand. The provided implementation, which calls `update_with`
+    /// just once, is right for types that don't count a line's appearances.
+    fn merge_with(&mut self, other: Self) {
+        self.update_with(other)
+    }
+
+    /// Called on an operand's value before each of its lines is processed.
+    /// The provided implementation does nothing; only types that record line
+    /// numbers need to override it.
+    fn next_line(&mut self) {}
 }
 
 /// When a `ZetSet` processes a line from an operand, it does one of two things:
@@ -43,34 +122,57 @@ pub trait LaterOperand {
 /// * If the line is already present in the set, `v.update_with(item)` is
 ///   called on its bookkeeping value `v`.
 ///
+/// Before processing each line, we call `item.next_line()`, so bookkeeping
+/// types that record where a line was first seen can keep count.
+///
+/// As lines are inserted, we charge the memory the set uses to a `Budget`, and
+/// stop inserting lines once it's exceeded; the caller checks the budget
+/// afterwards.
+///
 /// The `new` function inserts lines borrowed from its `slice` argument. The
-/// `insert_or_update` inserts `Cow::Owned` lines, so its `operand` argument
-/// need not outlive the `ZetSet` The `update_if_present` method only updates —
-/// it's used by the `Insert` and `Diff` operations, which only decrease the set
-/// returned by `new` and never add to it.
+/// `insert_or_update` method copies each new line into an `Arena`, and inserts
+/// a `Cow::Borrowed` key into the arena, so its `operand` argument need not
+/// outlive the `ZetSet`. (Without an arena, it inserts `Cow::Owned` lines.) The
+/// `update_if_present` method only updates — it's used by the `Intersect` and
+/// `Diff` operations, which only decrease the set returned by `new` and never
+/// add to it.
+///
+/// To read operands in parallel, `from_operand` and `updates_if_present` read
+/// an operand without changing the set, and `merge` and `replace_values` apply
+/// what they found.
 ///
 /// The `retain` method filters the set, using a function passed by the caller that
-/// looks at the `.retention_value()` of the bookkeeping item.
+/// looks at the `.retention_value()` of the bookkeeping item. (It's defined
+/// only for `Bookkeeping` types, which have retention values.)
 ///
-/// The `output_to` method prints the lines of the set, calling the bookkeeping
-/// item's `write_count` method (when appropriate) to prefix each line with the
-/// number of times it appears in the input, or the number of files it appears
-/// in.
-impl<'data, B: Bookkeeping> ZetSet<'data, B> {
+/// The `sort_by` and `reverse` methods reorder the set before output, and the
+/// `entries` and `get_index` methods give the lines and their bookkeeping
+/// values to the code in `operations` that writes them.
+impl<'data, B: Aggregate> ZetSet<'data, B> {
     /// Create a new `ZetSet`, with each key a line borrowed from `slice`, and
     /// value `item` for every line newly seen. If a line is already present,
-    /// with bookkeeping value `v`, update it by calling `v.update_with(item)`
+    /// with bookkeeping value `v`, update it by calling `v.update_with(item)`.
+    /// Both `slice` and the set are charged to `budget`; once it's exceeded,
+    /// we stop.
     ///
     /// This is very much like the `insert_or_update` method, which uses
     /// `for_byte_line`. But I had borrow-checker trouble when I tried that, so
     /// this code is a specialized version, with what would have been
     /// `for_byte_line` inlined by hand. See Andrew Gallant's `bstr` crate, in
     /// particular `bstr::io::for_byte_record_with_terminator`.
-    pub(crate) fn new(mut slice: &'data [u8], item: B) -> Self {
+    pub(crate) fn new(mut slice: &'data [u8], mut item: B, budget: &mut Budget) -> Self {
         let (bom, line_terminator) = output_info(slice);
         slice = &slice[bom.len()..];
         let mut set = CowSet::<B>::default();
+        budget.hold(slice.len());
         while let Some(end) = memchr(b'\n', slice) {
+            // Charge for the map's next growth before it happens
+            if set.len() == set.capacity() {
+                budget.charge(entries_size::<B>(set.capacity() * 2));
+            }
+            if budget.exceeded() {
+                return ZetSet { set, bom, line_terminator };
+            }
             let (mut line, rest) = slice.split_at(end);
             slice = &rest[1..];
             if let Some(&maybe_cr) = line.last() {
@@ -78,63 +180,231 @@ impl<'data, B: Bookkeeping> ZetSet<'data, B> {
                     line = &line[..line.len() - 1];
                 }
             }
+            item.next_line();
+            budget.read(line.len());
             set.entry(Cow::Borrowed(line)).and_modify(|v| v.update_with(item)).or_insert(item);
         }
+        if budget.exceeded() {
+            return ZetSet { set, bom, line_terminator };
+        }
         if !slice.is_empty() {
+            item.next_line();
+            budget.read(slice.len());
             set.entry(Cow::Borrowed(slice)).and_modify(|v| v.update_with(item)).or_insert(item);
         }
+        budget.charge(entries_size::<B>(set.capacity()));
         ZetSet { set, bom, line_terminator }
     }
 
-    /// For each line in `operand`, insert `line` as `Cow::Owned` to the
+    /// The memory used by the set: its entries, and the lines stored in
+    /// `arena` (which may be shared with other sets). Lines of the first
+    /// operand, borrowed from its contents, aren't counted here, nor are
+    /// `Cow::Owned` lines.
+    pub(crate) fn memory_used(&self, arena: Option<&Arena>) -> u64 {
+        let stored = arena.map_or(0, Arena::allocated_bytes);
+        entries_size::<B>(self.set.capacity()).saturating_add(stored as u64)
+    }
+
+    /// If `line` is present, with bookkeeping value `v`, call `update(v, item)`
+    /// and return `false`. Otherwise insert it with bookkeeping value `item` —
+    /// borrowed from a copy in `arena`, if we have one, and otherwise owned —
+    /// and return `true`. (The arena isn't kept in the `ZetSet` itself, since
+    /// an `Arena` can't be shared between threads, and a `ZetSet` must be.)
+    /// We hash `line` just once, whether or not it's present.
+    fn insert_or_else(
+        &mut self,
+        line: &[u8],
+        arena: Option<&'data Arena>,
+        item: B,
+        update: impl FnOnce(&mut B, B),
+    ) -> bool {
+        let hash = self.set.hasher().hash_one(line);
+        match self.set.raw_entry_mut_v1().from_key_hashed_nocheck(hash, line) {
+            RawEntryMut::Occupied(mut entry) => {
+                update(entry.get_mut(), item);
+                false
+            }
+            RawEntryMut::Vacant(entry) => {
+                let line = match arena {
+                    Some(arena) => Cow::Borrowed(&*arena.alloc_slice_copy(line)),
+                    None => Cow::Owned(line.to_vec()),
+                };
+                entry.insert_hashed_nocheck(hash, line, item);
+                true
+            }
+        }
+    }
+
+    /// For each line in `operand`, insert `line` (storing it in `arena`, if
+    /// given; see `insert_or_else`) to the
     /// underlying `IndexMap` if it is not already present, with bookkeeping
     /// value `item`. If `line` is already present, with bookkeeping value `v`,
-    /// update it by calling `v.update_with(item)`
-    pub(crate) fn insert_or_update(&mut self, operand: impl LaterOperand, item: B) -> Result<()> {
-        operand.for_byte_line(|line| {
-            self.set
-                .entry(Cow::from(line.to_vec()))
-                .and_modify(|v| v.update_with(item))
-                .or_insert(item);
+    /// update it by calling `v.update_with(item)`. Once `budget` is exceeded,
+    /// we stop reading `operand`.
+    pub(crate) fn insert_or_update(
+        &mut self,
+        operand: impl LaterOperand,
+        arena: Option<&'data Arena>,
+        budget: &mut Budget,
+        mut item: B,
+    ) -> Result<()> {
+        operand.try_for_byte_line(|line| {
+            if budget.exceeded() {
+                return ControlFlow::Break(());
+            }
+            item.next_line();
+            budget.read(line.len());
+            if self.insert_or_else(line, arena, item, B::update_with) {
+                budget.charge(self.memory_used(arena));
+            }
+            ControlFlow::Continue(())
         })
     }
 
     /// For each line in `operand` that is already present in the underlying
-    /// `IndexMap` with bookkeeping value `v`, call `v.update_with(item)`.
-    pub(crate) fn update_if_present(&mut self, operand: impl LaterOperand, item: B) -> Result<()> {
+    /// `IndexMap` with bookkeeping value `v`, call `v.update_with(item)`. If
+    /// `operand` is an index, we look each line of the set up in it instead.
+    pub(crate) fn update_if_present(
+        &mut self,
+        operand: impl LaterOperand,
+        mut item: B,
+    ) -> Result<()> {
+        if let Some(index) = operand.index() {
+            for (line, bookkeeping) in &mut self.set {
+                if index.contains(line) {
+                    bookkeeping.update_with(item);
+                }
+            }
+            return Ok(());
+        }
         operand.for_byte_line(|line| {
+            item.next_line();
             if let Some(bookkeeping) = self.set.get_mut(line) {
                 bookkeeping.update_with(item)
             }
         })
     }
 
-    /// Like `IndexMap`'s `.retain` method, but exposes just the bookkeeping
-    /// item's `.retention_value()`
-    pub(crate) fn retain(&mut self, keep: impl Fn(u32) -> bool) {
-        self.set.retain(|_k, v| keep(v.retention_value()));
+    /// Return a `ZetSet` of the lines of `operand` alone, as `insert_or_update`
+    /// would insert them into an empty set. Used to read operands in parallel:
+    /// the result can be merged into the main `ZetSet` with `merge`.
+    pub(crate) fn from_operand(operand: impl LaterOperand, item: B) -> Result<Self> {
+        let mut set = ZetSet { set: CowSet::default(), bom: b"", line_terminator: b"\n" };
+        set.insert_or_update(operand, None, &mut Budget::new(None), item)?;
+        Ok(set)
     }
 
-    /// Expose the underlying `ZetSet`'s `keys` method
-    pub(crate) fn keys(&self) -> map::Keys<Cow<[u8]>, B> {
-        self.set.keys()
+    /// Merge the lines of `other`, a set returned by `from_operand`, into this
+    /// set, in order. Lines not already present are stored in `arena`; for
+    /// lines already present, with bookkeeping value `v`, we
+    /// call `v.merge_with(item)`. The result is the same as if we'd called
+    /// `insert_or_update` on `other`'s operand.
+    pub(crate) fn merge(&mut self, other: ZetSet<'_, B>, arena: &'data Arena) {
+        for (line, item) in other.set {
+            self.insert_or_else(&line, Some(arena), item, B::merge_with);
+        }
     }
-    /// Expose the underlying `ZetSet`'s `iter` method
-    pub(crate) fn iter(&self) -> map::Iter<Cow<[u8]>, B> {
-        self.set.iter()
+
+    /// Like `update_if_present`, but rather than updating the set, return the
+    /// updates for the lines of `operand` that are present in the set. Used
+    /// to read operands in parallel: each update is meant to be applied to a
+    /// line's bookkeeping value `v` with `v.merge_with(update)`. As with
+    /// `update_if_present`, an index is consulted line by line of the set.
+    pub(crate) fn updates_if_present(
+        &self,
+        operand: impl LaterOperand,
+        mut item: B,
+    ) -> Result<IndexUpdates<B>> {
+        let mut updates = IndexUpdates::default();
+        if let Some(index) = operand.index() {
+            for (i, line) in self.set.keys().enumerate() {
+                if index.contains(line) {
+                    updates.insert(i, item);
+                }
+            }
+            return Ok(updates);
+        }
+        operand.for_byte_line(|line| {
+            item.next_line();
+            if let Some(index) = self.set.get_index_of(line) {
+                updates.entry(index).and_modify(|v: &mut B| v.update_with(item)).or_insert(item);
+            }
+        })?;
+        Ok(updates)
+    }
+
+    /// Replace the bookkeeping values of the set, in order, by `values`
+    pub(crate) fn replace_values(&mut self, values: Vec<B>) {
+        for (v, value) in self.set.values_mut().zip(values) {
+            *v = value;
+        }
+    }
+
+    /// Retain the lines whose index in the set has a `true` value in `keep`
+    pub(crate) fn retain_indices(&mut self, keep: &[bool]) {
+        let mut index = 0;
+        self.set.retain(|_k, _v| {
+            index += 1;
+            keep[index - 1]
+        });
+    }
+
+    /// The line at position `index` of the set, and its bookkeeping item.
+    /// Panics if `index` is out of bounds.
+    pub(crate) fn get_index(&self, index: usize) -> (&[u8], B) {
+        let (line, &item) = self.set.get_index(index).expect("ZetSet index out of bounds");
+        (line, item)
+    }
+
+    /// The number of lines in the set
+    pub(crate) fn len(&self) -> usize {
+        self.set.len()
+    }
+
+    /// Like `IndexMap`'s `.sort_by` method (a stable sort), but with each
+    /// line's key and bookkeeping item passed as separate arguments
+    pub(crate) fn sort_by(&mut self, mut cmp: impl FnMut(&[u8], B, &[u8], B) -> Ordering) {
+        self.set.sort_by(|a_line, &a, b_line, &b| cmp(a_line, a, b_line, b));
+    }
+
+    /// Reverse the order of the set's lines
+    pub(crate) fn reverse(&mut self) {
+        self.set.reverse();
+    }
+
+    /// The lines of the set, in order, each with its bookkeeping item. Everything
+    /// that reports the set's lines — writing them as text or structured
+    /// records, or passing them to a library caller — reads them from here.
+    pub(crate) fn entries(&self) -> impl Iterator<Item = (&[u8], B)> + Clone + '_ {
+        self.set.iter().map(|(line, &item)| (line.as_ref(), item))
     }
     /// Expose the underlying `ZetSet`'s `values` method
-    pub(crate) fn values(&self) -> map::Values<Cow<[u8]>, B> {
+    pub(crate) fn values(&self) -> map::Values<'_, Cow<'_, [u8]>, B> {
         self.set.values()
     }
 }
 
+impl<B: Bookkeeping> ZetSet<'_, B> {
+    /// Like `IndexMap`'s `.retain` method, but exposes just the bookkeeping
+    /// item's `.retention_value()`
+    pub(crate) fn retain(&mut self, keep: impl Fn(u32) -> bool) {
+        self.set.retain(|_k, v| keep(v.retention_value()));
+    }
+}
+
+/// The memory used by `capacity` entries of a `CowSet`: each entry's hash,
+/// key, and value, and its slot in the index table
+fn entries_size<B>(capacity: usize) -> u64 {
+    let entry = size_of::<(u64, Cow<[u8]>, B)>() + size_of::<usize>() + 1;
+    (capacity as u64).saturating_mul(entry as u64)
+}
+
 /// Returns `(bom, line_terminator)`, where `bom` is the (UTF-8) Byte Order
 /// Mark, or the empty string if `slice` has none, and `line_terminator` is
 /// `\r\n` if the first line of `slice` ends with `\r\n`, and `\n` if the first
 /// line ends just with `\n` (or is the only line in the file and has no line
 /// terminator).
-fn output_info(slice: &[u8]) -> (&'static [u8], &'static [u8]) {
+pub(crate) fn output_info(slice: &[u8]) -> (&'static [u8], &'static [u8]) {
     let mut bom: &'static [u8] = b"";
     let mut line_terminator: &'static [u8] = b"\n";
     if has_bom(slice) {
@@ -171,4 +441,44 @@ mod test {
     fn utf8_bom_is_correct() {
         assert_eq!([BOM_0, BOM_1, BOM_2], UTF8_BOM.as_bytes());
     }
+
+    #[derive(Clone, Copy)]
+    struct Seen;
+    impl Aggregate for Seen {
+        fn update_with(&mut self, _other: Self) {}
+    }
+
+    /// An operand of `0`, `1`, `2`, ... that counts the lines it's asked for
+    struct Counting<'a>(&'a mut usize);
+    impl LaterOperand for Counting<'_> {
+        fn for_byte_line(self, mut for_each_line: impl FnMut(&[u8])) -> Result<()> {
+            self.try_for_byte_line(|line| {
+                for_each_line(line);
+                ControlFlow::Continue(())
+            })
+        }
+
+        fn try_for_byte_line(
+            self,
+            mut for_each_line: impl FnMut(&[u8]) -> ControlFlow<()>,
+        ) -> Result<()> {
+            while *self.0 < 1_000_000 {
+                *self.0 += 1;
+                if for_each_line(self.0.to_string().as_bytes()).is_break() {
+                    break;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn insert_or_update_stops_reading_once_over_budget() {
+        let mut set = ZetSet::new(b"a\n", Seen, &mut Budget::new(None));
+        let mut budget = Budget::new(Some(10_000));
+        let mut read = 0;
+        set.insert_or_update(Counting(&mut read), None, &mut budget, Seen).unwrap();
+        assert!(budget.exceeded());
+        assert!(read < 10_000, "read {read} lines");
+    }
 }
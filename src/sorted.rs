@@ -0,0 +1,119 @@
+//! Provides `calculate_sorted`, used for `--sorted`. When every operand is
+//! already sorted (bytewise, as by `LC_ALL=C sort`), we don't need to remember
+//! the lines we've seen: like `comm`, we read all the operands at once, in
+//! step, always advancing past the smallest line. All the copies of that line,
+//! in every operand, are then at hand, so we can count them, decide whether
+//! the line belongs in the result, and write it immediately. So memory use is
+//! constant, and output begins as soon as the first result line is known.
+//!
+//! The result is written in sorted order — the order `--sort lexical` asks
+//! for. For `Diff` and `Intersect`, that's also the usual first-appearance
+//! order, but the other operations' results differ in order from those
+//! `calculate` writes. We don't support counts, since `calculate` pads them to
+//! the width of the largest, which we can't know until the end.
+use crate::hashed::{belongs_in, write_line, Counts};
+use crate::operands::{first_with_output_info, NextOperand, Remaining};
+use crate::operations::CountMode;
+use crate::operations::Operation::{self, Diff, Intersect};
+use crate::order::{Sequence, SortOrder};
+use crate::output::Output;
+use anyhow::{bail, Result};
+use std::io::Write;
+use std::iter;
+use std::mem;
+use std::path::PathBuf;
+
+/// Calculate `operation` over the files in `paths`, each of which must be
+/// sorted, writing the result in sorted order. We fail when we find a line
+/// that's out of order — by which time some of the result may have been
+/// written. We support only the plain text output format, without counts,
+/// and no order but sorted order.
+pub fn calculate_sorted(
+    operation: Operation,
+    log_type: CountMode,
+    output: &Output,
+    paths: &[PathBuf],
+    mut out: impl Write,
+) -> Result<()> {
+    if output.needs_tally() || !matches!(log_type, CountMode::None) {
+        bail!("--sorted supports only plain text output, without counts")
+    }
+    let sorted_order = matches!(output.sort, SortOrder::FirstSeen | SortOrder::Lexical);
+    if !sorted_order || output.order != Sequence::FirstSeen || output.reverse {
+        bail!("--sorted writes lines in sorted order (as --sort lexical does), and no other")
+    }
+    let Some((first, rest)) = paths.split_first() else {
+        bail!("--sorted needs at least one input file")
+    };
+    let all_files = u32::try_from(paths.len())?;
+    // As `--stream` does, we take the Byte Order Mark and line terminator
+    // from the first line, so standard input gets them right too
+    let (first, bom, line_terminator) = first_with_output_info(first)?;
+    let additive = !matches!(operation, Diff | Intersect);
+
+    let mut cursors = Vec::with_capacity(paths.len());
+    for operand in iter::once(Ok(first)).chain(Remaining::from(rest.to_vec())) {
+        cursors.push(Cursor::new(operand?)?);
+    }
+    out.write_all(bom)?;
+    let mut line = Vec::new();
+    while let Some(smallest) = cursors.iter().filter_map(Cursor::line).min() {
+        line.clear();
+        line.extend_from_slice(smallest);
+        // With `Diff` and `Intersect`, only lines of the first operand can be
+        // in the result
+        let in_first = cursors[0].line() == Some(&line);
+        let mut counts = Counts::new(0);
+        for (file_number, cursor) in (0..all_files).zip(&mut cursors) {
+            while cursor.line() == Some(&line) {
+                counts.see(file_number);
+                cursor.advance()?;
+            }
+        }
+        if (additive || in_first) && belongs_in(operation, counts, all_files) {
+            write_line(&mut out, None, &line, line_terminator)?;
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// One operand, read a line at a time, checking that its lines are in order
+struct Cursor {
+    operand: NextOperand,
+    /// The current line, unless we've reached the end of the operand
+    line: Option<Vec<u8>>,
+    /// The line before the current one, kept so we can reuse its buffer
+    previous: Vec<u8>,
+    /// The line number of the current line
+    lineno: u64,
+}
+impl Cursor {
+    fn new(operand: NextOperand) -> Result<Self> {
+        let mut cursor =
+            Cursor { operand, line: Some(Vec::new()), previous: Vec::new(), lineno: 0 };
+        cursor.advance()?;
+        Ok(cursor)
+    }
+    fn line(&self) -> Option<&[u8]> {
+        self.line.as_deref()
+    }
+    /// Move to the next line, failing if it's smaller than the current one
+    fn advance(&mut self) -> Result<()> {
+        let Some(current) = self.line.as_mut() else { return Ok(()) };
+        mem::swap(current, &mut self.previous);
+        if !self.operand.read_line(current)? {
+            self.line = None;
+            return Ok(());
+        }
+        self.lineno += 1;
+        if self.lineno > 1 && *current < self.previous {
+            bail!(
+                "{}: line {} is out of order (--sorted needs every input sorted bytewise, as by `LC_ALL=C sort`)",
+                self.operand.path_display(),
+                self.lineno
+            )
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,422 @@
+//! Provides `calculate_spilled`, used for `--memory-limit`. Rather than keeping
+//! every distinct line in memory at once, `calculate_spilled` works in three
+//! passes, using a temporary directory:
+//!
+//! 1. It reads the input once, writing each line to one of several *partition*
+//!    files, chosen by the line's hash — so all the occurrences of a given
+//!    line land in the same partition. Each line is written along with its
+//!    sequence number (its position in the input as a whole) and the number
+//!    of the operand it came from.
+//! 2. It reads the partitions one at a time, collecting each partition's
+//!    distinct lines and their counts in memory, and writes the lines that
+//!    belong in the result to a *run* file, in order of first appearance.
+//!    A partition too large for the memory limit is first split into smaller
+//!    partitions, using a differently-seeded hash.
+//! 3. It merges the runs by sequence number, so that the result lines are
+//!    written in the order they first appeared in the input, just as
+//!    `calculate` would write them.
+//!
+//! The memory limit is approximate: we estimate the memory needed for a
+//! partition as twice its size on disk.
+use crate::hashed::{belongs_in, write_line, Counts};
+use crate::operands::{first_with_output_info, Remaining};
+use crate::operations::CountMode;
+use crate::operations::Operation::{self, Diff, Intersect};
+use crate::output::Output;
+use crate::set::LaterOperand;
+use anyhow::{bail, Context, Result};
+use fxhash::FxBuildHasher;
+use indexmap::{map::Entry, IndexMap};
+use std::{
+    cell::Cell,
+    cmp::Reverse,
+    collections::BinaryHeap,
+    env,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Write},
+    iter, mem,
+    ops::ControlFlow,
+    path::{Path, PathBuf},
+    process,
+};
+use xxhash_rust::xxh3::xxh3_64_with_seed;
+
+/// The most partitions (or runs) we write or read at once
+const MAX_OPEN: usize = 256;
+/// How many times we'll split a partition before giving up and reading it
+/// anyway
+const MAX_DEPTH: u64 = 3;
+
+/// The number of partitions to split `size` bytes of input into, so that each
+/// partition fits within `memory_limit`
+fn partition_count(size: u64, memory_limit: u64) -> usize {
+    let wanted = size.saturating_mul(2).div_ceil(memory_limit);
+    usize::try_from(wanted).unwrap_or(MAX_OPEN).clamp(1, MAX_OPEN)
+}
+
+/// Calculate `operation` over the files in `paths`, as `calculate` would, but
+/// keeping the distinct lines of only one partition in memory at a time, with
+/// the goal of using no more than roughly `memory_limit` bytes. We support
+/// only the plain text output format, with or without counts, in
+/// first-appearance order.
+pub fn calculate_spilled(
+    operation: Operation,
+    log_type: CountMode,
+    output: &Output,
+    paths: &[PathBuf],
+    memory_limit: u64,
+    mut out: impl Write,
+) -> Result<()> {
+    if output.needs_tally() || output.reorders() {
+        bail!(
+            "--memory-limit supports only plain text output, with or without counts, in the \
+             order lines first appear"
+        )
+    }
+    let Some((first, rest)) = paths.split_first() else {
+        bail!("--memory-limit needs at least one input file")
+    };
+    let all_files = u32::try_from(paths.len())?;
+    // We read standard input only once, so we take the Byte Order Mark and
+    // line terminator from its first line as we spill it
+    let (first, bom, line_terminator) = first_with_output_info(first)?;
+    let spill = SpillDir::new()?;
+
+    // Pass 1: partition the input. We can't know how large standard input
+    // will be, so we go by the size of the files; pass 2 splits any
+    // partitions that turn out to be too large.
+    let memory_limit = memory_limit.max(1);
+    let known_size = paths.iter().filter_map(|path| fs::metadata(path).ok()).map(|m| m.len());
+    let count = partition_count(known_size.sum(), memory_limit);
+    let mut partitions = Partitions::create(&spill, count, 0)?;
+    let mut seq = 0_u64;
+    let operands = iter::once(Ok(first)).chain(Remaining::from(rest.to_vec()));
+    for (file_number, operand) in operands.enumerate() {
+        let file_number = u32::try_from(file_number)?;
+        let mut written: io::Result<()> = Ok(());
+        operand?.try_for_byte_line(|line| {
+            written = partitions.write(&Spilled { seq, file_number, line });
+            seq += 1;
+            if written.is_ok() {
+                ControlFlow::Continue(())
+            } else {
+                ControlFlow::Break(())
+            }
+        })?;
+        written.with_context(|| spill.cant_write())?;
+    }
+    let mut pending = partitions.finish(u64::MAX)?;
+
+    // Pass 2: sift each partition into a run, splitting oversized partitions
+    let additive = !matches!(operation, Diff | Intersect);
+    let mut runs = Vec::new();
+    let mut width = 0;
+    while let Some((path, depth, parent_size)) = pending.pop() {
+        let size = fs::metadata(&path).with_context(|| spill.cant_write())?.len();
+        // If splitting a partition didn't make it any smaller, its lines are
+        // all the same, and splitting it again won't help.
+        let splittable = depth < MAX_DEPTH && size < parent_size;
+        if size == 0 {
+            // An empty partition contributes nothing to the result
+        } else if splittable && size.saturating_mul(2) > memory_limit {
+            let count = partition_count(size, memory_limit).max(2);
+            let mut partitions = Partitions::create(&spill, count, depth + 1)?;
+            for_each_spilled(&path, |spilled| partitions.write(&spilled))?;
+            pending.extend(partitions.finish(size)?);
+        } else {
+            let mut lines = IndexMap::<Vec<u8>, (u64, Counts), FxBuildHasher>::default();
+            for_each_spilled(&path, |Spilled { seq, file_number, line }| {
+                let (_, counts) = match lines.entry(line.to_vec()) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) if additive || file_number == 0 => {
+                        entry.insert((seq, Counts::new(file_number)))
+                    }
+                    Entry::Vacant(_) => return Ok(()),
+                };
+                counts.see(file_number);
+                Ok(())
+            })?;
+            lines.retain(|_, (_, counts)| belongs_in(operation, *counts, all_files));
+            let (run, mut run_out) = spill.create()?;
+            for (line, &(seq, counts)) in &lines {
+                let count = counts.count(log_type);
+                if let Some(count) = count {
+                    width = width.max(output.line_count_text(count).len());
+                }
+                let run_line = RunLine { seq, count: count.unwrap_or(0), line };
+                run_line.write(&mut run_out).with_context(|| spill.cant_write())?;
+            }
+            run_out.flush().with_context(|| spill.cant_write())?;
+            runs.push(run);
+        }
+        fs::remove_file(&path).with_context(|| spill.cant_write())?;
+    }
+
+    // Pass 3: merge the runs — first, if there are too many to open at once,
+    // into fewer runs
+    while runs.len() > MAX_OPEN {
+        let group = runs.split_off(runs.len() - MAX_OPEN);
+        let (run, mut run_out) = spill.create()?;
+        merge(&group, |run_line| run_line.write(&mut run_out))?;
+        run_out.flush().with_context(|| spill.cant_write())?;
+        runs.insert(0, run);
+    }
+    out.write_all(bom)?;
+    merge(&runs, |RunLine { count, line, .. }| {
+        let count = log_type_count(log_type, count).map(|c| output.line_count_text(c));
+        let count = count.map(|count| format!("{count:>width$} "));
+        write_line(&mut out, count, line, line_terminator)
+    })?;
+    out.flush()?;
+    Ok(())
+}
+
+/// The count of a `RunLine` to report, if `log_type` asks for one
+fn log_type_count(log_type: CountMode, count: u32) -> Option<u32> {
+    match log_type {
+        CountMode::None => None,
+        CountMode::Lines | CountMode::Files => Some(count),
+    }
+}
+
+/// A line as written to a partition file, together with its sequence number
+/// and the operand it came from
+struct Spilled<'a> {
+    seq: u64,
+    file_number: u32,
+    line: &'a [u8],
+}
+impl Spilled<'_> {
+    fn write(&self, out: &mut impl Write) -> io::Result<()> {
+        write_record(out, self.seq, self.file_number, self.line)
+    }
+}
+
+/// A line of the result as written to a run file, together with its sequence
+/// number and the count to report with it (`0` if there's none)
+struct RunLine<'a> {
+    seq: u64,
+    count: u32,
+    line: &'a [u8],
+}
+impl RunLine<'_> {
+    fn write(&self, out: &mut impl Write) -> io::Result<()> {
+        write_record(out, self.seq, self.count, self.line)
+    }
+}
+
+/// Write a `Spilled` or `RunLine` record. Both have the same layout: the
+/// sequence number, the `u32` field, and the length of the line, then the line.
+fn write_record(out: &mut impl Write, seq: u64, number: u32, line: &[u8]) -> io::Result<()> {
+    out.write_all(&seq.to_le_bytes())?;
+    out.write_all(&number.to_le_bytes())?;
+    out.write_all(&(line.len() as u64).to_le_bytes())?;
+    out.write_all(line)
+}
+
+/// Call `f` on each record of the partition file at `path`
+fn for_each_spilled(path: &Path, mut f: impl FnMut(Spilled) -> io::Result<()>) -> Result<()> {
+    let cant_read = || format!("Can't read temporary file: {}", path.display());
+    let mut reader = RecordReader::open(path).with_context(cant_read)?;
+    while reader.advance().with_context(cant_read)? {
+        f(reader.spilled()).with_context(cant_read)?;
+    }
+    Ok(())
+}
+
+/// Merge the `runs`, each ordered by sequence number, calling `f` on each
+/// record in order of sequence number
+fn merge(runs: &[PathBuf], mut f: impl FnMut(RunLine) -> io::Result<()>) -> Result<()> {
+    let cant_read = || "Can't read temporary files".to_string();
+    let mut readers = Vec::with_capacity(runs.len());
+    let mut heap = BinaryHeap::with_capacity(runs.len());
+    for path in runs {
+        let mut reader = RecordReader::open(path).with_context(cant_read)?;
+        if reader.advance().with_context(cant_read)? {
+            heap.push(Reverse((reader.seq, readers.len())));
+        }
+        readers.push(reader);
+    }
+    while let Some(Reverse((_, index))) = heap.pop() {
+        let reader = &mut readers[index];
+        f(reader.run_line())?;
+        if reader.advance().with_context(cant_read)? {
+            heap.push(Reverse((reader.seq, index)));
+        }
+    }
+    Ok(())
+}
+
+/// Reads `Spilled` or `RunLine` records one at a time
+struct RecordReader {
+    reader: BufReader<File>,
+    seq: u64,
+    number: u32,
+    line: Vec<u8>,
+}
+impl RecordReader {
+    fn open(path: &Path) -> io::Result<Self> {
+        let reader = BufReader::new(File::open(path)?);
+        Ok(RecordReader { reader, seq: 0, number: 0, line: Vec::new() })
+    }
+    /// Read the next record, returning `false` at the end of the file
+    fn advance(&mut self) -> io::Result<bool> {
+        let mut seq = [0; 8];
+        match self.reader.read_exact(&mut seq) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+            result => result?,
+        }
+        let mut number = [0; 4];
+        self.reader.read_exact(&mut number)?;
+        let mut len = [0; 8];
+        self.reader.read_exact(&mut len)?;
+        let len = usize::try_from(u64::from_le_bytes(len))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.line.resize(len, 0);
+        self.reader.read_exact(&mut self.line)?;
+        self.seq = u64::from_le_bytes(seq);
+        self.number = u32::from_le_bytes(number);
+        Ok(true)
+    }
+    /// The current record, read from a partition file
+    fn spilled(&self) -> Spilled<'_> {
+        Spilled { seq: self.seq, file_number: self.number, line: &self.line }
+    }
+    /// The current record, read from a run file
+    fn run_line(&self) -> RunLine<'_> {
+        RunLine { seq: self.seq, count: self.number, line: &self.line }
+    }
+}
+
+/// A set of partition files being written. A line's partition is chosen by a
+/// hash seeded with the partition `depth`, so that splitting a partition
+/// spreads its lines out.
+struct Partitions<'a> {
+    spill: &'a SpillDir,
+    depth: u64,
+    files: Vec<(PathBuf, BufWriter<File>)>,
+}
+impl<'a> Partitions<'a> {
+    fn create(spill: &'a SpillDir, count: usize, depth: u64) -> Result<Self> {
+        let files = (0..count).map(|_| spill.create()).collect::<Result<_>>()?;
+        Ok(Partitions { spill, depth, files })
+    }
+    fn write(&mut self, spilled: &Spilled) -> io::Result<()> {
+        let hash = xxh3_64_with_seed(spilled.line, self.depth);
+        let index = usize::try_from(hash % self.files.len() as u64).unwrap_or(0);
+        spilled.write(&mut self.files[index].1)
+    }
+    /// Flush the partitions, returning their paths and depth, and the size of
+    /// the partition they were split from
+    fn finish(self, parent_size: u64) -> Result<Vec<(PathBuf, u64, u64)>> {
+        let mut finished = Vec::with_capacity(self.files.len());
+        for (path, mut out) in self.files {
+            out.flush().with_context(|| self.spill.cant_write())?;
+            finished.push((path, self.depth, parent_size));
+        }
+        Ok(finished)
+    }
+}
+
+/// A temporary directory, removed (with its contents) when dropped. On Unix,
+/// only its owner can use it, since the files in it hold copies of the input.
+struct SpillDir {
+    path: PathBuf,
+    next: Cell<u64>,
+}
+impl SpillDir {
+    fn new() -> Result<Self> {
+        let base = env::temp_dir();
+        let mut attempt = 0;
+        loop {
+            let path = base.join(format!("zet-{}-{attempt}", process::id()));
+            match Self::builder().create(&path) {
+                Ok(()) => return Ok(SpillDir { path, next: Cell::new(0) }),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists && attempt < 100 => {
+                    attempt += 1;
+                }
+                Err(e) => {
+                    let cant_create = || format!("Can't create directory: {}", path.display());
+                    return Err(e).with_context(cant_create);
+                }
+            }
+        }
+    }
+    /// A `DirBuilder` for the directory: it must not already exist, and on
+    /// Unix it's private to its owner, whatever the umask
+    fn builder() -> fs::DirBuilder {
+        let mut builder = fs::DirBuilder::new();
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+        builder
+    }
+    /// Create a new file in the directory
+    fn create(&self) -> Result<(PathBuf, BufWriter<File>)> {
+        let n = self.next.replace(self.next.get() + 1);
+        let path = self.path.join(format!("{n}.spill"));
+        let file = File::create(&path).with_context(|| self.cant_write())?;
+        Ok((path, BufWriter::new(file)))
+    }
+    fn cant_write(&self) -> String {
+        format!("Can't write temporary files in {}", self.path.display())
+    }
+}
+impl Drop for SpillDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(mem::take(&mut self.path));
+    }
+}
+
+#[allow(clippy::pedantic)]
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assert_fs::{prelude::*, TempDir};
+
+    #[cfg(unix)]
+    #[test]
+    fn the_spill_directory_is_private_to_its_owner() {
+        use std::os::unix::fs::PermissionsExt;
+        let spill = SpillDir::new().unwrap();
+        let mode = fs::metadata(&spill.path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+    }
+
+    #[test]
+    fn spilled_records_are_read_back_as_written() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.child("run").path().to_path_buf();
+        let mut out = File::create(&path).unwrap();
+        Spilled { seq: 7, file_number: 2, line: b"seven" }.write(&mut out).unwrap();
+        Spilled { seq: 9, file_number: 0, line: b"" }.write(&mut out).unwrap();
+        drop(out);
+        let mut read = Vec::new();
+        for_each_spilled(&path, |s| {
+            read.push((s.seq, s.file_number, s.line.to_vec()));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(read, vec![(7, 2, b"seven".to_vec()), (9, 0, Vec::new())]);
+    }
+
+    #[test]
+    fn runs_are_merged_by_sequence_number() {
+        let dir = TempDir::new().unwrap();
+        let runs = [dir.child("a").path().to_path_buf(), dir.child("b").path().to_path_buf()];
+        let contents: [&[(u64, &[u8])]; 2] = [&[(1, b"one"), (4, b"four")], &[(2, b"two")]];
+        for (path, lines) in runs.iter().zip(contents) {
+            let mut out = File::create(path).unwrap();
+            for &(seq, line) in lines {
+                RunLine { seq, count: seq as u32 * 10, line }.write(&mut out).unwrap();
+            }
+        }
+        let mut merged = Vec::new();
+        merge(&runs, |r| {
+            merged.push((r.count, String::from_utf8(r.line.to_vec()).unwrap()));
+            Ok(())
+        })
+        .unwrap();
+        let expected = [(10, "one"), (20, "two"), (40, "four")];
+        assert_eq!(merged, expected.map(|(count, line)| (count, line.to_string())));
+    }
+}
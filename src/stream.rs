@@ -0,0 +1,81 @@
+//! Provides `stream_union`, used for `--stream`. Unlike the other operations,
+//! `Union` without counts doesn't need to see the whole input before writing
+//! anything: a line belongs in the result as soon as it's first seen. So
+//! `stream_union` reads the operands a line at a time, remembering the lines
+//! it has seen, and writes each new line immediately. That lets `zet` remove
+//! duplicates from an endless feed, like the output of `tail -f`.
+//!
+//! The result is exactly what `calculate` would write for `Union`: the Byte
+//! Order Mark and line terminator come from the first operand (we can see
+//! them as soon as we've read its first line), and lines are written in order
+//! of first appearance.
+use crate::operands::{reader_keeping_bom, trim_line_terminator, Remaining};
+use crate::operations::CountMode;
+use crate::operations::Operation::{self, Union};
+use crate::output::Output;
+use crate::set::{output_info, Arena};
+use anyhow::{bail, Result};
+use fxhash::FxHashSet;
+use std::io::Write;
+use std::path::PathBuf;
+
+/// Write the union of the files in `paths`, each line as soon as it is first
+/// seen. If `flush_each_line` is set, we flush `out` after each line, so that
+/// a reader downstream sees it right away; otherwise we leave buffering to
+/// `out`.
+pub fn stream_union(
+    operation: Operation,
+    log_type: CountMode,
+    output: &Output,
+    paths: &[PathBuf],
+    flush_each_line: bool,
+    mut out: impl Write,
+) -> Result<()> {
+    if operation != Union || !matches!(log_type, CountMode::None) || output.needs_tally() {
+        bail!("--stream works only for `zet union`, without counts, in plain text")
+    }
+    if output.reorders() {
+        bail!("--stream writes lines in the order they first appear, and no other")
+    }
+    let Some((first, rest)) = paths.split_first() else {
+        bail!("--stream needs at least one input file")
+    };
+
+    let arena = Arena::new();
+    let mut seen = FxHashSet::<&[u8]>::default();
+    let mut first = reader_keeping_bom(first)?;
+    let mut line = Vec::new();
+    let found_line = first.read_raw_line(&mut line)?;
+    let (bom, line_terminator) = output_info(&line);
+    out.write_all(bom)?;
+    let mut write_if_new = |line: &[u8]| -> Result<()> {
+        if !seen.contains(line) {
+            seen.insert(arena.alloc_slice_copy(line));
+            out.write_all(line)?;
+            out.write_all(line_terminator)?;
+            if flush_each_line {
+                out.flush()?;
+            }
+        }
+        Ok(())
+    };
+
+    // The first line, without its Byte Order Mark. If that leaves nothing at
+    // all, not even a line terminator, the first operand has no lines.
+    line.drain(..bom.len());
+    if found_line && !line.is_empty() {
+        trim_line_terminator(&mut line);
+        write_if_new(&line)?;
+    }
+    while first.read_line(&mut line)? {
+        write_if_new(&line)?;
+    }
+    for operand in Remaining::from(rest.to_vec()) {
+        let mut operand = operand?;
+        while operand.read_line(&mut line)? {
+            write_if_new(&line)?;
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
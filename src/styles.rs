@@ -1,9 +1,13 @@
+#[cfg(feature = "cli")]
 use clap::ValueEnum;
+#[cfg(feature = "cli")]
 use enable_ansi_support::enable_ansi_support;
+#[cfg(feature = "cli")]
 use once_cell::sync::OnceCell;
 use std::fmt;
 
-#[derive(Debug, Clone, ValueEnum)]
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
 pub enum ColorChoice {
     Auto,
     Always,
@@ -11,13 +15,36 @@ pub enum ColorChoice {
 }
 #[derive(Debug, Clone, Copy)]
 pub struct StyleSheet {
+    /// Does this style sheet color anything? (If not, every prefix is `None`.)
+    colored: bool,
     app_prefix: Option<&'static str>,
     item_prefix: Option<&'static str>,
     title_prefix: Option<&'static str>,
     error_prefix: Option<&'static str>,
     literal_prefix: Option<&'static str>,
+    count_prefix: Option<&'static str>,
+    file_prefix: Option<&'static str>,
+    lineno_prefix: Option<&'static str>,
+    separator_prefix: Option<&'static str>,
+}
+impl Default for StyleSheet {
+    /// The default style sheet is plain text, with no color.
+    fn default() -> Self {
+        NEVER
+    }
 }
 impl StyleSheet {
+    /// The style sheet the `zet` command uses for color: counts in yellow,
+    /// file names in magenta, and so on
+    #[must_use]
+    pub fn colored() -> Self {
+        ALWAYS
+    }
+    /// Does this style sheet color anything?
+    #[must_use]
+    pub fn is_colored(&self) -> bool {
+        self.colored
+    }
     #[must_use]
     pub fn app_name<'a>(&self, content: &'a str) -> StyledStr<'a> {
         StyledStr { prefix: self.app_prefix, content }
@@ -38,13 +65,33 @@ impl StyleSheet {
     pub fn literal<'a>(&self, content: &'a str) -> StyledStr<'a> {
         StyledStr { prefix: self.literal_prefix, content }
     }
+    /// The style of a line's count in the result
+    #[must_use]
+    pub fn count<'a>(&self, content: &'a str) -> StyledStr<'a> {
+        StyledStr { prefix: self.count_prefix, content }
+    }
+    /// The style of a file name in provenance labels
+    #[must_use]
+    pub fn file_name<'a>(&self, content: &'a str) -> StyledStr<'a> {
+        StyledStr { prefix: self.file_prefix, content }
+    }
+    /// The style of a line number in provenance labels
+    #[must_use]
+    pub fn lineno<'a>(&self, content: &'a str) -> StyledStr<'a> {
+        StyledStr { prefix: self.lineno_prefix, content }
+    }
+    /// The style of the separators between counts, labels, and lines
+    #[must_use]
+    pub fn separator<'a>(&self, content: &'a str) -> StyledStr<'a> {
+        StyledStr { prefix: self.separator_prefix, content }
+    }
 }
 
 pub struct StyledStr<'a> {
     prefix: Option<&'static str>,
     content: &'a str,
 }
-impl<'a> StyledStr<'a> {
+impl StyledStr<'_> {
     #[must_use]
     pub fn len(&self) -> usize {
         self.content.len()
@@ -59,7 +106,7 @@ impl<'a> StyledStr<'a> {
         self.content.as_bytes().find_not_byteset(b" ").unwrap_or(self.len())
     }
 }
-impl<'a> fmt::Display for StyledStr<'a> {
+impl fmt::Display for StyledStr<'_> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         if let Some(prefix) = self.prefix {
             write!(f, "{}{}{}", prefix, self.content, RESET)
@@ -73,22 +120,35 @@ const BOLD_RED: &str = "\x1B[31;1m";
 const GREEN: &str = "\x1B[32m";
 const BOLD_GREEN: &str = "\x1B[32;1m";
 const YELLOW: &str = "\x1B[33m";
+const MAGENTA: &str = "\x1B[35m";
+const CYAN: &str = "\x1B[36m";
 const RESET: &str = "\x1B[m";
 
 const NEVER: StyleSheet = StyleSheet {
+    colored: false,
     app_prefix: None,
     item_prefix: None,
     title_prefix: None,
     error_prefix: None,
     literal_prefix: None,
+    count_prefix: None,
+    file_prefix: None,
+    lineno_prefix: None,
+    separator_prefix: None,
 };
 const ALWAYS: StyleSheet = StyleSheet {
+    colored: true,
     app_prefix: Some(BOLD_GREEN),
     item_prefix: Some(GREEN),
     title_prefix: Some(YELLOW),
     error_prefix: Some(BOLD_RED),
     literal_prefix: Some(YELLOW),
+    count_prefix: Some(YELLOW),
+    file_prefix: Some(MAGENTA),
+    lineno_prefix: Some(GREEN),
+    separator_prefix: Some(CYAN),
 };
+#[cfg(feature = "cli")]
 fn auto() -> StyleSheet {
     use supports_color::Stream;
     let use_color = enable_ansi_support().is_ok() && supports_color::on(Stream::Stdout).is_some();
@@ -99,11 +159,15 @@ fn auto() -> StyleSheet {
     }
 }
 
+#[cfg(feature = "cli")]
 static COLOR_CHOICE: OnceCell<ColorChoice> = OnceCell::new();
+#[cfg(feature = "cli")]
 static STYLE_SHEET: OnceCell<StyleSheet> = OnceCell::new();
+#[cfg(feature = "cli")]
 pub(crate) fn set_color_choice(cc: ColorChoice) {
     COLOR_CHOICE.set(cc).expect("set_color_choice may only be called once");
 }
+#[cfg(feature = "cli")]
 pub(crate) fn global_style() -> &'static StyleSheet {
     if let Some(style) = STYLE_SHEET.get() {
         style
@@ -122,6 +186,20 @@ pub(crate) fn global_style() -> &'static StyleSheet {
     }
 }
 
+/// The style sheet for the result of a set operation. With `--color auto`
+/// (the default), we use color only if the result is going to standard output
+/// and standard output is a terminal that supports color. So piped output, and
+/// output to a file, is plain text unless the user asks for `--color always`.
+#[cfg(feature = "cli")]
+#[must_use]
+pub fn result_style(to_stdout: bool) -> StyleSheet {
+    match COLOR_CHOICE.get() {
+        Some(ColorChoice::Always) => *global_style(),
+        Some(ColorChoice::Auto) if to_stdout => *global_style(),
+        _ => NEVER,
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -135,4 +213,10 @@ mod test {
             assert_eq!(choice.title(contents).len(), contents.len());
         }
     }
+
+    #[test]
+    fn only_the_colored_style_sheet_is_colored() {
+        assert!(StyleSheet::colored().is_colored());
+        assert!(!StyleSheet::default().is_colored());
+    }
 }
@@ -0,0 +1,186 @@
+//! Provides the `Template` type used by the `--format` option. A template is
+//! text with placeholders — `{line}`, `{count_lines}`, `{count_files}`,
+//! `{files}` (the names of the operands the line is in, separated by commas),
+//! `{first_file}` and `{first_lineno}` — that is rendered once for each output
+//! line. In a template, `{{` and `}}` stand for literal braces, and `\t`, `\n`,
+//! `\r`, and `\\` stand for a tab, newline, carriage return, and backslash.
+use crate::output::{Output, Record};
+use anyhow::Result;
+use std::io;
+
+/// A parsed `--format` template
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template(Vec<Piece>);
+
+/// The parts of a template: literal text, and the placeholders
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Piece {
+    Literal(String),
+    Line,
+    CountLines,
+    CountFiles,
+    Files,
+    FirstFile,
+    FirstLineno,
+}
+
+const PLACEHOLDERS: &[(&str, Piece)] = &[
+    ("line", Piece::Line),
+    ("count_lines", Piece::CountLines),
+    ("count_files", Piece::CountFiles),
+    ("files", Piece::Files),
+    ("first_file", Piece::FirstFile),
+    ("first_lineno", Piece::FirstLineno),
+];
+
+impl Template {
+    /// Parse `text` as a template, returning an error message if it has an
+    /// unknown placeholder or escape sequence, or an unmatched brace.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut pieces = Vec::new();
+        let mut literal = String::new();
+        let mut chars = text.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => literal.push(match chars.next() {
+                    Some('t') => '\t',
+                    Some('n') => '\n',
+                    Some('r') => '\r',
+                    Some('\\') => '\\',
+                    Some(other) => return Err(format!("unknown escape sequence '\\{other}'")),
+                    None => return Err("the template ends with a lone '\\'".to_string()),
+                }),
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '}' => return Err("unmatched '}' (use '}}' for a literal brace)".to_string()),
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '{' => {
+                    let rest = chars.as_str();
+                    let Some(end) = rest.find('}') else {
+                        return Err("unmatched '{' (use '{{' for a literal brace)".to_string());
+                    };
+                    let name = &rest[..end];
+                    let Some((_, piece)) = PLACEHOLDERS.iter().find(|(n, _)| *n == name) else {
+                        let known = PLACEHOLDERS.iter().map(|(n, _)| format!("{{{n}}}"));
+                        let known = known.collect::<Vec<_>>().join(", ");
+                        return Err(format!("unknown placeholder '{{{name}}}' (expected {known})"));
+                    };
+                    if !literal.is_empty() {
+                        pieces.push(Piece::Literal(std::mem::take(&mut literal)));
+                    }
+                    pieces.push(piece.clone());
+                    chars = rest[end + 1..].chars();
+                }
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            pieces.push(Piece::Literal(literal));
+        }
+        Ok(Template(pieces))
+    }
+
+    /// Does the template report where lines first appeared?
+    pub(crate) fn uses_provenance(&self) -> bool {
+        self.0.iter().any(|piece| matches!(piece, Piece::FirstFile | Piece::FirstLineno))
+    }
+
+    /// Does the template name the operands each line is in?
+    pub(crate) fn uses_files(&self) -> bool {
+        self.0.contains(&Piece::Files)
+    }
+
+    /// Write `record` as the template directs. Missing values (which can't
+    /// happen when `record` comes from `Tallied` bookkeeping) are written as
+    /// empty text.
+    pub(crate) fn render(
+        &self,
+        output: &Output,
+        record: &Record,
+        out: &mut impl io::Write,
+    ) -> Result<()> {
+        for piece in &self.0 {
+            match piece {
+                Piece::Literal(text) => out.write_all(text.as_bytes())?,
+                Piece::Line => out.write_all(record.line)?,
+                Piece::CountLines => {
+                    if let Some(count) = record.count_lines {
+                        out.write_all(output.line_count_text(count).as_bytes())?
+                    }
+                }
+                Piece::CountFiles => {
+                    if let Some(count) = record.count_files {
+                        write!(out, "{count}")?
+                    }
+                }
+                Piece::Files => {
+                    if let Some(operands) = record.operands {
+                        let mut separator = "";
+                        for file_number in operands.iter() {
+                            out.write_all(separator.as_bytes())?;
+                            out.write_all(output.operand_name(file_number).as_bytes())?;
+                            separator = ",";
+                        }
+                    }
+                }
+                Piece::FirstFile => {
+                    if let Some(p) = record.provenance {
+                        out.write_all(output.operand_name(p.file_number).as_bytes())?
+                    }
+                }
+                Piece::FirstLineno => {
+                    if let Some(p) = record.provenance {
+                        write!(out, "{}", p.lineno)?
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[allow(clippy::pedantic)]
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::output::{OperandSet, Provenance};
+
+    fn rendered(template: &str, record: &Record) -> String {
+        let operand_names = vec!["a.txt".to_string(), "b.txt".to_string()];
+        let output = Output { operand_names, ..Output::default() };
+        let mut out = Vec::new();
+        Template::parse(template).unwrap().render(&output, record, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn placeholders_braces_and_escapes_are_rendered() {
+        let record = Record {
+            line: b"it's",
+            count_lines: Some(3),
+            count_files: Some(1),
+            provenance: Some(Provenance { file_number: 0, lineno: 7 }),
+            operands: Some(OperandSet::just(0)),
+        };
+        assert_eq!(rendered("{count_lines}\\t{line}", &record), "3\tit's");
+        assert_eq!(rendered("{line} ({count_files} files)", &record), "it's (1 files)");
+        assert_eq!(rendered("{line}: {files}", &record), "it's: a.txt");
+        let mut operands = OperandSet::just(1);
+        operands.add(OperandSet::just(0));
+        let record = Record { operands: Some(operands), ..record };
+        assert_eq!(rendered("{line}: {files}", &record), "it's: a.txt,b.txt");
+        assert_eq!(rendered("{{{first_file}:{first_lineno}}}\\\\", &record), "{a.txt:7}\\");
+    }
+
+    #[test]
+    fn bad_templates_are_rejected() {
+        for bad in ["{lines}", "{line", "line}", "\\x", "x\\"] {
+            assert!(Template::parse(bad).is_err(), "for {bad}");
+        }
+    }
+}
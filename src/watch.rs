@@ -0,0 +1,377 @@
+//! Provides `Watcher` and `Cache`, used for `--watch`. With `--watch`, `main`
+//! calculates the result, then waits for the `Watcher` to report that some of
+//! the operands have changed, and calculates it again, and so on until it's
+//! interrupted.
+//!
+//! The `Cache` keeps the contents of every operand between calculations, so
+//! that only the operands that changed are read again. (The set operation
+//! itself is calculated from scratch each time — it's reading the input that
+//! takes the time.) So with `--watch`, the whole input stays in memory, on top
+//! of the memory each calculation uses, and `--max-memory` counts only the
+//! latter: `--watch` is for inputs that fit in memory comfortably. (An index
+//! is memory-mapped, not read, so it costs little.)
+//!
+//! On Linux, the `Watcher` uses inotify, watching the directory of each
+//! operand, so that it sees files replaced by editors that write a new file
+//! and rename it over the old one. Elsewhere, it checks the size and
+//! modification time of each operand a few times a second.
+use crate::index::{is_index, SetIndex};
+use crate::operands::{decode_if_utf16, use_stdin};
+use crate::set::LaterOperand;
+use anyhow::{bail, Context, Result};
+use bstr::ByteSlice;
+use fxhash::FxHashMap;
+#[cfg(target_os = "linux")]
+use std::ffi::OsString;
+use std::fs;
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// How long the operands must be left alone after a change before we
+/// recalculate, so that a burst of writes (or an editor's save) leads to one
+/// recalculation rather than several
+const DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// Bail if any of `paths` is standard input, which can't be watched
+pub fn check_watchable(paths: &[PathBuf]) -> Result<()> {
+    if paths.is_empty() || paths.iter().any(|path| use_stdin(path)) {
+        bail!("--watch can't watch standard input; name the input files")
+    }
+    Ok(())
+}
+
+/// The contents of every operand, as of the last time each was read
+#[derive(Default)]
+pub struct Cache {
+    entries: FxHashMap<PathBuf, Entry>,
+}
+
+/// A cached operand: its contents, and the size and modification time it
+/// had when read
+struct Entry {
+    len: u64,
+    modified: Option<SystemTime>,
+    contents: Contents,
+}
+
+/// The contents of an operand: its lines (translated from UTF-16, if need
+/// be), or an index
+enum Contents {
+    Lines(Vec<u8>),
+    Index(SetIndex),
+}
+
+impl Cache {
+    /// Read each operand in `paths` that's in `changed`, isn't cached yet, or
+    /// whose size or modification time differs from when we read it. Returns
+    /// the number of operands read.
+    pub fn refresh(&mut self, paths: &[PathBuf], changed: &[PathBuf]) -> Result<usize> {
+        self.entries.retain(|path, _| paths.contains(path));
+        let mut read = 0;
+        for path in paths {
+            let cant_read = || format!("Can't read file: {}", path.display());
+            let metadata = fs::metadata(path).with_context(cant_read)?;
+            let (len, modified) = (metadata.len(), metadata.modified().ok());
+            let fresh = self.entries.get(path).is_some_and(|entry| {
+                entry.len == len && entry.modified == modified && !changed.contains(path)
+            });
+            if !fresh {
+                let contents = if is_index(path) {
+                    Contents::Index(SetIndex::open(path)?)
+                } else {
+                    Contents::Lines(decode_if_utf16(fs::read(path).with_context(cant_read)?))
+                };
+                self.entries.insert(path.clone(), Entry { len, modified, contents });
+                read += 1;
+            }
+        }
+        Ok(read)
+    }
+
+    /// The contents of `path`, to be used as the first operand
+    pub fn first_operand(&self, path: &Path) -> Result<&[u8]> {
+        match self.entry(path)? {
+            Contents::Lines(contents) => Ok(contents),
+            Contents::Index(_) => bail!(
+                "{}: zet can't read the lines of an index, so it can't be the operand read first",
+                path.display()
+            ),
+        }
+    }
+
+    /// `path`, to be used as a later operand
+    pub fn later_operand(&self, path: &Path) -> Result<CachedOperand<'_>> {
+        let path_display = path.display().to_string();
+        match self.entry(path)? {
+            Contents::Lines(contents) => Ok(CachedOperand::Lines(contents, path_display)),
+            Contents::Index(index) => Ok(CachedOperand::Index(index)),
+        }
+    }
+
+    /// The cached contents of `path`
+    fn entry(&self, path: &Path) -> Result<&Contents> {
+        match self.entries.get(path) {
+            Some(entry) => Ok(&entry.contents),
+            None => bail!("This can't happen: {} wasn't read", path.display()),
+        }
+    }
+}
+
+/// A second or later operand, read from the `Cache`
+pub enum CachedOperand<'a> {
+    /// The operand's contents, and its path formatted for error messages
+    Lines(&'a [u8], String),
+    Index(&'a SetIndex),
+}
+
+impl LaterOperand for CachedOperand<'_> {
+    fn index(&self) -> Option<&SetIndex> {
+        match self {
+            CachedOperand::Lines(..) => None,
+            CachedOperand::Index(index) => Some(index),
+        }
+    }
+
+    fn for_byte_line(self, mut for_each_line: impl FnMut(&[u8])) -> Result<()> {
+        self.try_for_byte_line(|line| {
+            for_each_line(line);
+            ControlFlow::Continue(())
+        })
+    }
+
+    /// As for `NextOperand`, a Byte Order Mark and line terminators aren't
+    /// part of the lines.
+    fn try_for_byte_line(
+        self,
+        mut for_each_line: impl FnMut(&[u8]) -> ControlFlow<()>,
+    ) -> Result<()> {
+        match self {
+            CachedOperand::Lines(contents, _) => {
+                let contents = contents.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(contents);
+                let _ = contents.lines().try_for_each(&mut for_each_line);
+                Ok(())
+            }
+            CachedOperand::Index(_) => bail!(
+                "zet can't read the lines of an index; it can only be a later operand of `diff`, \
+                 or an operand of `intersect`"
+            ),
+        }
+    }
+}
+
+/// Watches a set of operands for changes
+pub struct Watcher {
+    paths: Vec<PathBuf>,
+    #[cfg(target_os = "linux")]
+    inotify: inotify::Inotify,
+    #[cfg(not(target_os = "linux"))]
+    snapshot: Vec<Option<(u64, Option<SystemTime>)>>,
+}
+
+impl Watcher {
+    /// Start watching `paths`
+    pub fn new(paths: &[PathBuf]) -> Result<Self> {
+        let paths = paths.to_vec();
+        #[cfg(target_os = "linux")]
+        {
+            let inotify = inotify::Inotify::new(&paths)?;
+            Ok(Watcher { paths, inotify })
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let snapshot = snapshot(&paths);
+            Ok(Watcher { paths, snapshot })
+        }
+    }
+
+    /// Wait until some of the operands change, and then until they've been
+    /// left alone for `DEBOUNCE`. Returns the operands that changed.
+    pub fn wait(&mut self) -> Result<Vec<PathBuf>> {
+        let mut changed = Vec::new();
+        #[cfg(target_os = "linux")]
+        {
+            let mut timeout = None; // Wait as long as it takes for the first change
+            while let Some(names) = self.inotify.read(timeout)? {
+                for path in &self.paths {
+                    // An event without a name could be about any file
+                    let about = |name: &Option<OsString>| {
+                        name.as_deref().is_none_or(|name| path.file_name() == Some(name))
+                    };
+                    if names.iter().any(about) && !changed.contains(path) {
+                        changed.push(path.clone());
+                    }
+                }
+                if !changed.is_empty() {
+                    timeout = Some(DEBOUNCE);
+                }
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let mut quiet = Duration::ZERO;
+            while changed.is_empty() || quiet < DEBOUNCE {
+                std::thread::sleep(DEBOUNCE / 4);
+                let now = snapshot(&self.paths);
+                let before = changed.len();
+                for ((path, old), new) in self.paths.iter().zip(&self.snapshot).zip(&now) {
+                    if old != new && !changed.contains(path) {
+                        changed.push(path.clone());
+                    }
+                }
+                quiet = if now == self.snapshot && changed.len() == before {
+                    quiet + DEBOUNCE / 4
+                } else {
+                    Duration::ZERO
+                };
+                self.snapshot = now;
+            }
+        }
+        Ok(changed)
+    }
+}
+
+/// The size and modification time of each of `paths`, or `None` for those we
+/// can't see
+#[cfg(not(target_os = "linux"))]
+fn snapshot(paths: &[PathBuf]) -> Vec<Option<(u64, Option<SystemTime>)>> {
+    let metadata = |path| fs::metadata(path).ok().map(|m| (m.len(), m.modified().ok()));
+    paths.iter().map(metadata).collect()
+}
+
+/// A minimal inotify interface: enough to watch the directories of a set of
+/// paths and read the names of files that change in them
+#[cfg(target_os = "linux")]
+mod inotify {
+    use anyhow::{Context, Result};
+    use std::ffi::{CString, OsStr, OsString};
+    use std::io;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+
+    /// The events that mean a file in a watched directory may have changed
+    const EVENTS: u32 = libc::IN_CLOSE_WRITE
+        | libc::IN_MODIFY
+        | libc::IN_ATTRIB
+        | libc::IN_CREATE
+        | libc::IN_DELETE
+        | libc::IN_MOVED_FROM
+        | libc::IN_MOVED_TO;
+
+    pub(super) struct Inotify {
+        fd: OwnedFd,
+    }
+
+    impl Inotify {
+        /// Watch the directory of each of `paths`
+        pub(super) fn new(paths: &[PathBuf]) -> Result<Self> {
+            // SAFETY: `inotify_init1` has no preconditions; we check its result.
+            let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error()).context("Can't start watching files");
+            }
+            // SAFETY: `fd` is a file descriptor we just opened, and own.
+            let inotify = Inotify { fd: unsafe { OwnedFd::from_raw_fd(fd) } };
+            let mut directories: Vec<&Path> = Vec::new();
+            for path in paths {
+                let directory = match path.parent() {
+                    Some(parent) if !parent.as_os_str().is_empty() => parent,
+                    _ => Path::new("."),
+                };
+                if !directories.contains(&directory) {
+                    inotify.add_watch(directory)?;
+                    directories.push(directory);
+                }
+            }
+            Ok(inotify)
+        }
+
+        /// Watch `directory` for `EVENTS`
+        fn add_watch(&self, directory: &Path) -> Result<()> {
+            let cant_watch = || format!("Can't watch directory: {}", directory.display());
+            let name = CString::new(directory.as_os_str().as_bytes()).with_context(cant_watch)?;
+            // SAFETY: `name` is a valid C string, and `self.fd` is open.
+            let wd = unsafe { libc::inotify_add_watch(self.fd.as_raw_fd(), name.as_ptr(), EVENTS) };
+            if wd < 0 {
+                return Err(io::Error::last_os_error()).with_context(cant_watch);
+            }
+            Ok(())
+        }
+
+        /// Wait up to `timeout` (or forever, for `None`) for events, and
+        /// return the names of the files they're about — `None` for an event
+        /// without a name, which could be about any file. Returns `None` if
+        /// the time ran out.
+        pub(super) fn read(
+            &self,
+            timeout: Option<Duration>,
+        ) -> Result<Option<Vec<Option<OsString>>>> {
+            let millis = timeout.map_or(-1, |t| i32::try_from(t.as_millis()).unwrap_or(i32::MAX));
+            let mut poll =
+                libc::pollfd { fd: self.fd.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+            // SAFETY: `poll` is a valid `pollfd`, and we pass a count of 1.
+            let ready = unsafe { libc::poll(&raw mut poll, 1, millis) };
+            if ready < 0 {
+                let error = io::Error::last_os_error();
+                if error.kind() == io::ErrorKind::Interrupted {
+                    return Ok(Some(Vec::new()));
+                }
+                return Err(error).context("Can't watch files");
+            }
+            if ready == 0 {
+                return Ok(None);
+            }
+            let mut buffer = vec![0_u8; 64 * 1024];
+            // SAFETY: `buffer` is valid for writes of its length.
+            let n = unsafe {
+                libc::read(self.fd.as_raw_fd(), buffer.as_mut_ptr().cast(), buffer.len())
+            };
+            let n = usize::try_from(n)
+                .map_err(|_| io::Error::last_os_error())
+                .context("Can't watch files")?;
+            Ok(Some(names(&buffer[..n])))
+        }
+    }
+
+    /// The file names of the `inotify_event` structures in `events`
+    fn names(mut events: &[u8]) -> Vec<Option<OsString>> {
+        const HEADER: usize = size_of::<libc::inotify_event>();
+        let mut names = Vec::new();
+        while events.len() >= HEADER {
+            // The header is `wd: i32, mask: u32, cookie: u32, len: u32`
+            let field = |at: usize| u32::from_ne_bytes(events[at..at + 4].try_into().unwrap());
+            let (mask, len) = (field(4), field(12) as usize);
+            let name = events.get(HEADER..HEADER + len).unwrap_or_default();
+            let name = name.split(|&b| b == 0).next().unwrap_or_default();
+            if mask & libc::IN_Q_OVERFLOW != 0 || name.is_empty() {
+                names.push(None);
+            } else {
+                names.push(Some(OsStr::from_bytes(name).to_os_string()));
+            }
+            events = events.get(HEADER + len..).unwrap_or_default();
+        }
+        names
+    }
+
+    #[allow(clippy::pedantic)]
+    #[cfg(test)]
+    mod test {
+        use super::*;
+        use std::os::unix::ffi::OsStringExt;
+
+        #[test]
+        fn names_are_read_from_packed_events() {
+            let mut events = Vec::new();
+            for (mask, name) in [(libc::IN_MODIFY, &b"a.txt\0\0\0"[..]), (libc::IN_Q_OVERFLOW, b"")]
+            {
+                for field in [1, mask, 0, name.len() as u32] {
+                    events.extend_from_slice(&field.to_ne_bytes());
+                }
+                events.extend_from_slice(name);
+            }
+            assert_eq!(names(&events), [Some(OsString::from_vec(b"a.txt".to_vec())), None]);
+        }
+    }
+}
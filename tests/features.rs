@@ -6,7 +6,7 @@ use assert_fs::{prelude::*, TempDir};
 use indexmap::IndexMap;
 use itertools::Itertools;
 use once_cell::sync::Lazy;
-use zet::args::OpName::{self, *};
+use zet::operations::Operation::{self, *};
 
 fn main_binary() -> Command {
     Command::cargo_bin("zet").unwrap()
@@ -41,9 +41,9 @@ fn prints_version_with_V_or_version_flag() {
         assert!(output.lines().collect::<Vec<_>>().len() == 1);
     }
 }
-const OP_NAMES: [OpName; 7] =
+const OP_NAMES: [Operation; 7] =
     [Intersect, Union, Diff, Single, SingleByFile, Multiple, MultipleByFile];
-fn subcommand_for(op: OpName) -> &'static str {
+fn subcommand_for(op: Operation) -> &'static str {
     match op {
         Union => "union",
         Intersect => "intersect",
@@ -57,7 +57,7 @@ fn subcommand_for(op: OpName) -> &'static str {
 fn subcommands() -> [&'static str; 7] {
     OP_NAMES.map(subcommand_for)
 }
-fn flagged_subcommands_for(op: OpName) -> Vec<String> {
+fn flagged_subcommands_for(op: Operation) -> Vec<String> {
     fn flag(name: &str) -> Vec<String> {
         let mut result = vec![name.to_string(), format!("{name} --count-none")];
         match name {
@@ -192,10 +192,10 @@ struct TestInput {
     y: usize,
     z: usize,
     tag: &'static str,
-    expect: Vec<OpName>,
+    expect: Vec<Operation>,
 }
 impl TestInput {
-    fn should_be_in(&self, op: OpName) -> bool {
+    fn should_be_in(&self, op: Operation) -> bool {
         self.expect.contains(&op)
     }
 }
@@ -225,10 +225,10 @@ impl fmt::Debug for TestInput {
 // x.txt, y.txt, and/or z.txt.  The x, y, and z fields tell how many times to
 // put the formatted record into each file, and expect field tells whether we
 // expect the formatted record to appear in the output of the command associated
-// with each OpName.
+// with each Operation.
 //
 static INPUT: Lazy<Vec<TestInput>> = Lazy::new(|| {
-    use OpName::{
+    use Operation::{
         Diff as D, Intersect as I, Multiple as M, MultipleByFile as MBF, Single as S,
         SingleByFile as SBF, Union as U,
     };
@@ -245,7 +245,7 @@ static INPUT: Lazy<Vec<TestInput>> = Lazy::new(|| {
         TestInput { x: 0, y: 0, z: 1, tag: "In z once", expect: vec![U, S, SBF] },
     ]
 });
-fn xpected(op: OpName) -> Vec<String> {
+fn xpected(op: Operation) -> Vec<String> {
     INPUT.iter().filter(|inp| inp.should_be_in(op)).map(|inp| format!("{inp:?}")).collect()
 }
 fn text_for(xyz: impl Fn(&TestInput) -> usize) -> Vec<String> {
@@ -274,7 +274,7 @@ fn counts() -> IndexMap<String, usize> {
     }
     count_of
 }
-fn xpected_with_count(op: OpName) -> Vec<String> {
+fn xpected_with_count(op: Operation) -> Vec<String> {
     let count_of = counts();
     INPUT
         .iter()
@@ -441,3 +441,469 @@ fn zet_terminates_every_output_line_with_the_line_terminator_of_the_first_input_
         }
     }
 }
+
+#[test]
+fn json_and_ndjson_output_carry_both_counts_and_optional_provenance() {
+    let temp = TempDir::new().unwrap();
+    let a = &path_with(&temp, "a.txt", "a\nb\na\n", Encoding::Plain);
+    let b = &path_with(&temp, "b.txt", "b\n\"c\"\n", Encoding::Plain);
+
+    let output = run(["union --output-format json", a, b]).unwrap();
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        concat!(
+            "[\n",
+            r#"{"line":"a","count_lines":2,"count_files":1},"#,
+            "\n",
+            r#"{"line":"b","count_lines":2,"count_files":2},"#,
+            "\n",
+            r#"{"line":"\"c\"","count_lines":1,"count_files":1}"#,
+            "\n]\n"
+        )
+    );
+
+    let output = run(["multiple --output-format ndjson --provenance", a, b]).unwrap();
+    let expected = [
+        r#"{"line":"a","count_lines":2,"count_files":1,"first_file":"A","first_lineno":1}"#,
+        r#"{"line":"b","count_lines":2,"count_files":2,"first_file":"A","first_lineno":2}"#,
+    ];
+    let expected = expected.map(|record| record.replace('A', a.as_str()) + "\n").join("");
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), expected);
+
+    let output = run(["diff --output-format json", a, a]).unwrap();
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "[]\n");
+}
+
+#[test]
+fn tsv_output_has_a_header_and_count_position_moves_the_counts() {
+    let temp = TempDir::new().unwrap();
+    let a = &path_with(&temp, "a.txt", "a\nb\na\n", Encoding::Plain);
+    let b = &path_with(&temp, "b.txt", "b\nc\n", Encoding::Plain);
+
+    let output = run(["union --output-format tsv", a, b]).unwrap();
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "count_lines\tcount_files\tline\n2\t1\ta\n2\t2\tb\n1\t1\tc\n"
+    );
+
+    let output = run(["union --count-files --count-position suffix", a, b]).unwrap();
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "a 1\nb 2\nc 1\n");
+}
+
+#[test]
+fn sort_orders_break_ties_by_first_appearance() {
+    let temp = TempDir::new().unwrap();
+    let a = &path_with(&temp, "a.txt", "v10\nb\nv2\na\nb\n", Encoding::Plain);
+    let b = &path_with(&temp, "b.txt", "a\nv2\nc\n", Encoding::Plain);
+
+    for (flags, expected) in [
+        ("--sort first-seen", "v10 b v2 a c"),
+        ("--sort first-seen --reverse", "c a v2 b v10"),
+        ("--sort last-seen", "v10 b a v2 c"),
+        ("--sort lexical", "a b c v10 v2"),
+        ("--sort natural", "a b c v2 v10"),
+        ("--sort natural -r", "v10 v2 c b a"),
+        ("--sort count", "b v2 a v10 c"),
+        ("--sort count --reverse", "v10 c b v2 a"),
+        ("--sort files", "v2 a v10 b c"),
+    ] {
+        let output = run(["union", flags, a, b]).unwrap();
+        let result = String::from_utf8(output.stdout).unwrap().split_whitespace().join(" ");
+        assert_eq!(result, expected, "for {flags}");
+    }
+}
+
+#[test]
+fn top_and_bottom_select_by_count_after_sifting() {
+    let temp = TempDir::new().unwrap();
+    let a = &path_with(&temp, "a.txt", "a\nb\nb\nc\nc\nc\nc\nd\n", Encoding::Plain);
+    let b = &path_with(&temp, "b.txt", "d\nb\ne\n", Encoding::Plain);
+
+    for (flags, expected) in [
+        ("union --top 2", "b c"),
+        ("union --top 2 --sort count", "c b"),
+        ("union --bottom 2", "a e"),
+        ("union --count-files --top 2", "b d"),
+        ("multiple --files --top 1", "b"),
+        ("single --top 5", "a e"),
+        ("union --top 0", ""),
+    ] {
+        let output = run([flags, a, b]).unwrap();
+        let result = String::from_utf8(output.stdout).unwrap();
+        let lines = result.lines().map(|line| line.split(' ').next_back().unwrap()).join(" ");
+        assert_eq!(lines, expected, "for {flags}");
+    }
+}
+
+#[test]
+fn order_last_seen_moves_lines_to_their_last_appearance() {
+    let temp = TempDir::new().unwrap();
+    let a = &path_with(&temp, "a.txt", "x=1\ny=1\nx=1\nz=3\n", Encoding::Plain);
+    let b = &path_with(&temp, "b.txt", "y=1\nw=2\n", Encoding::Plain);
+
+    for (flags, expected) in [
+        ("union --order last-seen", "x=1 z=3 y=1 w=2"),
+        ("union --order last-seen --reverse", "w=2 y=1 z=3 x=1"),
+        ("union --sort last-seen", "x=1 z=3 y=1 w=2"),
+        ("union --sort last-seen --reverse", "w=2 y=1 z=3 x=1"),
+        ("union --sort last-seen --order first-seen", "x=1 y=1 z=3 w=2"),
+        ("union --order last-seen --sort count", "x=1 y=1 z=3 w=2"),
+        ("union --order last-seen --sort files", "y=1 x=1 z=3 w=2"),
+        ("multiple --order last-seen", "x=1 y=1"),
+    ] {
+        let output = run([flags, a, b]).unwrap();
+        let result = String::from_utf8(output.stdout).unwrap().split_whitespace().join(" ");
+        assert_eq!(result, expected, "for {flags}");
+    }
+}
+
+#[test]
+fn emit_writes_other_results_to_their_own_files() {
+    let temp = TempDir::new().unwrap();
+    let a = &path_with(&temp, "a.txt", "a\nb\nb\nc\n", Encoding::Plain);
+    let b = &path_with(&temp, "b.txt", "b\nd\nd\n", Encoding::Plain);
+    let common = temp.child("common.txt");
+    let only_first = temp.child("only-first.txt");
+    let emits = format!(
+        "--emit intersect={} --emit diff={}",
+        common.path().display(),
+        only_first.path().display()
+    );
+
+    let output = run(["union --count", &emits, a, b]).unwrap();
+    let union = String::from_utf8(output.stdout).unwrap().split_whitespace().join(" ");
+    assert_eq!(union, "1 a 3 b 1 c 2 d");
+    common.assert("3 b\n");
+    only_first.assert("1 a\n1 c\n");
+}
+
+#[test]
+fn output_file_can_also_be_an_input_and_is_untouched_on_failure() {
+    let temp = TempDir::new().unwrap();
+    let a = temp.child("a.txt");
+    a.write_str("a\nb\n").unwrap();
+    let b = &path_with(&temp, "b.txt", "b\nc\n", Encoding::Plain);
+    let a_path = a.path().display().to_string();
+
+    let output = run(["union -o", &a_path, &a_path, b]).unwrap();
+    assert!(output.stdout.is_empty());
+    a.assert("a\nb\nc\n");
+
+    let missing = temp.child("missing.txt").path().display().to_string();
+    run(["union -o", &a_path, &a_path, &missing]).assert().failure();
+    a.assert("a\nb\nc\n");
+    assert_eq!(std::fs::read_dir(temp.path()).unwrap().count(), 2);
+}
+
+#[test]
+fn counts_and_provenance_are_colored_only_when_asked_for_or_on_a_terminal() {
+    let temp = TempDir::new().unwrap();
+    let a = &path_with(&temp, "a.txt", "a\nb\nb\n", Encoding::Plain);
+
+    let output = run(["union --count --provenance", a]).unwrap();
+    assert!(!output.stdout.contains(&b'\x1B'));
+
+    let output = run(["union --count --color always", a]).unwrap();
+    let result = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(result, "\x1B[33m1\x1B[m\x1B[36m \x1B[ma\n\x1B[33m2\x1B[m\x1B[36m \x1B[mb\n");
+}
+
+#[test]
+fn format_templates_replace_the_text_layout() {
+    let temp = TempDir::new().unwrap();
+    let a = &path_with(&temp, "a.txt", "x\ny\ny\n", Encoding::Plain);
+    let b = &path_with(&temp, "b.txt", "y\n", Encoding::Plain);
+
+    let output =
+        run(["multiple", a, b, "--format", r"{line}\t{count_lines}/{count_files}"]).unwrap();
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "y\t3/2\n");
+
+    path_with(&temp, "c.txt", "z\nx\n", Encoding::Plain);
+    for flags in ["", "--threads 2", "--base 3"] {
+        let output = run(["union a.txt b.txt c.txt --format {line}:{files}", flags])
+            .current_dir(temp.path())
+            .unwrap();
+        let result = String::from_utf8(output.stdout).unwrap();
+        assert_eq!(result, "x:a.txt,c.txt\ny:a.txt,b.txt\nz:c.txt\n", "for {flags}");
+    }
+
+    run(["union --format {count} --", a]).assert().failure();
+}
+
+#[test]
+fn histogram_counts_lines_by_how_often_they_occur() {
+    let temp = TempDir::new().unwrap();
+    let a = &path_with(&temp, "a.txt", "x\ny\ny\nz\nz\nz\nz\n", Encoding::Plain);
+    let b = &path_with(&temp, "b.txt", "x\n", Encoding::Plain);
+
+    let output = run(["union --histogram lines", a, b]).unwrap();
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "2\t2\n4\t1\n");
+    let output = run(["union --histogram lines --buckets log", a, b]).unwrap();
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "1\t0\n2\t2\n3-10\t1\n");
+    let output = run(["union --histogram files", a, b]).unwrap();
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "1\t2\n2\t1\n");
+}
+
+#[test]
+fn threads_give_the_same_result_and_report_the_first_failing_operand() {
+    let temp = TempDir::new().unwrap();
+    let a = &path_with(&temp, "a.txt", "x\ny\ny\n", Encoding::Plain);
+    let b = &path_with(&temp, "b.txt", "z\ny\n", Encoding::Plain);
+    let c = &path_with(&temp, "c.txt", "w\nx\n", Encoding::Plain);
+
+    let sequential = run(["union --count", a, b, c, b]).unwrap();
+    let parallel = run(["union --count --threads 3", a, b, c, b]).unwrap();
+    assert_eq!(parallel.stdout, sequential.stdout);
+
+    let missing = temp.child("missing.txt").path().display().to_string();
+    let output = run(["union --threads 2", a, b, &missing, c]).unwrap_err();
+    let stderr = String::from_utf8(output.as_output().unwrap().stderr.clone()).unwrap();
+    assert!(stderr.contains("missing.txt"), "{stderr}");
+}
+
+#[test]
+fn hash_only_gives_the_same_result_but_cant_read_stdin_twice() {
+    let temp = TempDir::new().unwrap();
+    let a = &path_with(&temp, "a.txt", "x\ny\ny\n", Encoding::Plain);
+    let b = &path_with(&temp, "b.txt", "z\ny\n", Encoding::Plain);
+
+    for op in ["union", "diff", "intersect", "single --count", "multiple --count-files"] {
+        let expected = run([op, a, b]).unwrap();
+        let hashed = run(["--hash-only", op, a, b]).unwrap();
+        assert_eq!(hashed.stdout, expected.stdout, "for {op}");
+    }
+    let verified = run(["--hash-only --verify-hashes union --count", a, b]).unwrap();
+    assert_eq!(String::from_utf8(verified.stdout).unwrap(), "1 x\n3 y\n1 z\n");
+
+    let output = run(["--hash-only union --count -", b]).stdin(File::open(a).unwrap()).unwrap_err();
+    let stderr = String::from_utf8(output.as_output().unwrap().stderr.clone()).unwrap();
+    assert!(stderr.contains("standard input twice"), "{stderr}");
+    // Standard input read once keeps its Byte Order Mark and line terminator
+    let crlf = &path_with(&temp, "crlf.txt", "a\r\nb\r\na\r\n", Encoding::UTF8);
+    let expected = run(["union -", b]).stdin(File::open(crlf).unwrap()).unwrap();
+    let hashed = run(["--hash-only union -", b]).stdin(File::open(crlf).unwrap()).unwrap();
+    assert_eq!(hashed.stdout, expected.stdout);
+    assert!(hashed.stdout.starts_with(b"\xEF\xBB\xBFa\r\nb\r\n"));
+
+    for flags in ["--sort lexical", "--sort natural", "--reverse", "--order last-seen"] {
+        run(["--hash-only union", flags, a, b]).unwrap_err();
+    }
+}
+
+#[test]
+fn memory_limit_spills_to_disk_but_gives_the_same_result() {
+    let temp = TempDir::new().unwrap();
+    let many = (0..500).map(|n| format!("{}\n", n % 70)).collect::<String>();
+    let a = &path_with(&temp, "a.txt", &many, Encoding::Plain);
+    let b = &path_with(&temp, "b.txt", "z\n1\n3\n3\n", Encoding::Plain);
+
+    for op in ["union", "diff", "intersect --count", "single --count", "multiple --files"] {
+        let expected = run([op, a, b]).unwrap();
+        let spilled = run(["--memory-limit 200", op, a, b]).unwrap();
+        assert_eq!(spilled.stdout, expected.stdout, "for {op}");
+    }
+    let expected = run(["union --count", a, b]).unwrap();
+    let mut spilled = run(["--memory-limit 1K union --count -", b]);
+    let spilled = spilled.stdin(File::open(a).unwrap()).unwrap();
+    assert_eq!(spilled.stdout, expected.stdout);
+    // Standard input's Byte Order Mark and line terminator are kept, too
+    let crlf = &path_with(&temp, "crlf.txt", "a\r\nb\r\na\r\n", Encoding::UTF8);
+    let expected = run(["union -", b]).stdin(File::open(crlf).unwrap()).unwrap();
+    let spilled = run(["--memory-limit 1K union -", b]).stdin(File::open(crlf).unwrap()).unwrap();
+    assert_eq!(spilled.stdout, expected.stdout);
+    assert!(spilled.stdout.starts_with(b"\xEF\xBB\xBFa\r\nb\r\n"));
+
+    for flags in ["--sort lexical", "--sort natural", "--reverse", "--order last-seen"] {
+        run(["--memory-limit 200 union", flags, a, b]).unwrap_err();
+    }
+}
+
+#[test]
+fn sorted_merges_sorted_inputs_and_rejects_unsorted_ones() {
+    let temp = TempDir::new().unwrap();
+    let a = &path_with(&temp, "a.txt", "b\nb\nd\nf\n", Encoding::Plain);
+    let b = &path_with(&temp, "b.txt", "a\nb\nc\nf\n", Encoding::Plain);
+    let unsorted = &path_with(&temp, "unsorted.txt", "a\nc\nb\n", Encoding::Plain);
+
+    let union = run(["--sorted union", a, b]).unwrap();
+    assert_eq!(String::from_utf8(union.stdout).unwrap(), "a\nb\nc\nd\nf\n");
+    let expected = run(["union --sort lexical", a, b]).unwrap();
+    assert_eq!(run(["--sorted union --sort lexical", a, b]).unwrap().stdout, expected.stdout);
+    for op in ["diff", "intersect"] {
+        let expected = run([op, a, b]).unwrap();
+        let merged = run(["--sorted", op, a, b]).unwrap();
+        assert_eq!(merged.stdout, expected.stdout, "for {op}");
+    }
+    for flags in ["--count", "--count-files", "--sort natural", "--reverse", "--order last-seen"] {
+        run(["--sorted union", flags, a, b]).unwrap_err();
+    }
+    // Standard input keeps its Byte Order Mark and line terminator
+    let crlf = &path_with(&temp, "crlf.txt", "a\r\nb\r\nb\r\ne\r\n", Encoding::UTF8);
+    let expected = run(["union --sort lexical -", b]).stdin(File::open(crlf).unwrap()).unwrap();
+    let merged = run(["--sorted union -", b]).stdin(File::open(crlf).unwrap()).unwrap();
+    assert_eq!(merged.stdout, expected.stdout);
+    assert!(merged.stdout.starts_with(b"\xEF\xBB\xBFa\r\nb\r\n"));
+
+    let output = run(["--sorted union", a, unsorted]).unwrap_err();
+    let stderr = String::from_utf8(output.as_output().unwrap().stderr.clone()).unwrap();
+    assert!(stderr.contains("unsorted.txt: line 3 is out of order"), "{stderr}");
+}
+
+#[test]
+fn stream_writes_exactly_what_union_does() {
+    let temp = TempDir::new().unwrap();
+    let crlf = &path_with(&temp, "crlf.txt", "b\r\na\r\nb\r\n", Encoding::UTF8);
+    let utf16 = &path_with(&temp, "utf16.txt", "c\na\n", Encoding::LE16);
+    let plain = &path_with(&temp, "plain.txt", "a\nd", Encoding::Plain);
+
+    for operands in [[crlf, utf16, plain], [utf16, plain, crlf], [plain, crlf, utf16]] {
+        let expected = run(["union"]).args(operands).unwrap();
+        let streamed = run(["--stream union"]).args(operands).unwrap();
+        assert_eq!(streamed.stdout, expected.stdout, "for {operands:?}");
+    }
+    let expected = run(["union", utf16, plain]).unwrap();
+    let mut streamed = run(["--stream union -", plain]);
+    let streamed = streamed.stdin(File::open(utf16).unwrap()).unwrap();
+    assert_eq!(streamed.stdout, expected.stdout);
+
+    run(["--stream union --count", plain]).unwrap_err();
+    run(["--stream diff", plain]).unwrap_err();
+    for flags in ["--sort lexical", "--sort natural", "--sort last-seen", "--order last-seen", "-r"]
+    {
+        run(["--stream union", flags, plain]).unwrap_err();
+    }
+}
+
+#[test]
+fn reading_another_operand_first_gives_the_same_result() {
+    let temp = TempDir::new().unwrap();
+    let big = &path_with(&temp, "big.txt", "a\r\nb\r\nc\r\nd\r\ne\r\nf\r\nb\r\n", Encoding::UTF8);
+    let small = &path_with(&temp, "small.txt", "f\nb\n", Encoding::Plain);
+    let other = &path_with(&temp, "other.txt", "b\nx\nf\nc\n", Encoding::Plain);
+
+    // `intersect` reads `small` first by itself, but writes `big`'s order,
+    // byte order mark, and line terminator
+    let result = run(["intersect", big, small, other]).unwrap();
+    assert_eq!(String::from_utf8(result.stdout).unwrap(), "\u{FEFF}b\r\nf\r\n");
+    for command in ["union --count", "single --provenance", "multiple --sort last-seen"] {
+        let expected = run([command, big, small, other]).unwrap().stdout;
+        for base in ["2", "3"] {
+            let result = run([command, "--base", base, big, small, other]).unwrap();
+            assert_eq!(result.stdout, expected, "for {command} --base {base}");
+        }
+    }
+    let result = run(["union --count --base 2", big, small, other]).unwrap();
+    let expected = "\u{FEFF}1 a\r\n4 b\r\n2 c\r\n1 d\r\n1 e\r\n3 f\r\n1 x\r\n";
+    assert_eq!(String::from_utf8(result.stdout).unwrap(), expected);
+
+    run(["diff --base 2", big, small]).unwrap_err();
+    run(["union --base 3", big, small]).unwrap_err();
+}
+
+#[test]
+fn max_memory_falls_back_to_spilling_or_explains_itself() {
+    let temp = TempDir::new().unwrap();
+    let lines: String = (0..5000).map(|n| format!("line {n}\n")).collect();
+    let big = &path_with(&temp, "big.txt", &lines, Encoding::Plain);
+    let other = &path_with(&temp, "other.txt", "x\nline 7\n", Encoding::Plain);
+
+    let expected = run(["union --count", big, other]).unwrap();
+    let fallen_back = run(["union --count --max-memory 20K", big, other]).unwrap();
+    assert_eq!(fallen_back.stdout, expected.stdout);
+
+    // Standard input can't be read twice, and only `calculate` writes JSON
+    let mut from_stdin = run(["union --max-memory 20K -", other]);
+    let error = from_stdin.stdin(File::open(big).unwrap()).unwrap_err();
+    let stderr = String::from_utf8(error.as_output().unwrap().stderr.clone()).unwrap();
+    assert!(stderr.contains("<stdin>: zet would need more than"), "{stderr}");
+    let error = run(["union --output-format json --max-memory 20K", big, other]).unwrap_err();
+    let stderr = String::from_utf8(error.as_output().unwrap().stderr.clone()).unwrap();
+    assert!(stderr.contains("big.txt: zet would need about"), "{stderr}");
+    // Nor does spilling reorder its result
+    for flags in ["--sort lexical", "--sort natural", "--order last-seen", "-r"] {
+        let error = run(["union --max-memory 20K", flags, big, other]).unwrap_err();
+        let stderr = String::from_utf8(error.as_output().unwrap().stderr.clone()).unwrap();
+        assert!(stderr.contains("big.txt: zet would need about"), "for {flags}: {stderr}");
+    }
+
+    let verbose = run(["union --verbose --max-memory 1G", big, other]).unwrap();
+    assert_eq!(verbose.stdout, run(["union", big, other]).unwrap().stdout);
+    assert!(String::from_utf8(verbose.stderr).unwrap().starts_with("zet: peak memory use"));
+}
+
+#[test]
+fn an_index_answers_for_its_source_in_diff_and_intersect() {
+    let temp = TempDir::new().unwrap();
+    let block = &path_with(&temp, "block.txt", "b\nx\nzz\n", Encoding::UTF8);
+    let feed = &path_with(&temp, "feed.txt", "a\r\nb\r\nc\r\nx\r\n", Encoding::Plain);
+    let index = &temp.path().join("block.zidx").to_string_lossy().into_owned();
+    run(["index build", block, "-o", index]).unwrap();
+
+    for command in ["diff", "intersect"] {
+        let expected = run([command, feed, block]).unwrap().stdout;
+        assert_eq!(run([command, feed, index]).unwrap().stdout, expected, "for {command}");
+    }
+    assert_eq!(run(["intersect", index, feed]).unwrap().stdout, b"b\r\nx\r\n");
+    run(["diff", index, feed]).unwrap_err();
+    run(["union", feed, index]).unwrap_err();
+
+    // Only the usual way of reading operands can look lines up in an index
+    for flags in ["--hash-only", "--memory-limit 1K", "--sorted", "--emit union=-"] {
+        let error = run(["diff", flags, feed, index]).unwrap_err();
+        let stderr = String::from_utf8(error.as_output().unwrap().stderr.clone()).unwrap();
+        let option = flags.split(' ').next().unwrap();
+        assert!(
+            stderr.contains(&format!(
+                "block.zidx: zet can't read the lines of an index, as {option}"
+            )),
+            "{stderr}"
+        );
+    }
+    run(["union --stream", feed, index]).unwrap_err();
+    // ... so running out of memory can't fall back to --memory-limit
+    let lines: String = (0..5000).map(|n| format!("line {n}\n")).collect();
+    let big = &path_with(&temp, "big.txt", &lines, Encoding::Plain);
+    let error = run(["diff --max-memory 20K", big, index]).unwrap_err();
+    let stderr = String::from_utf8(error.as_output().unwrap().stderr.clone()).unwrap();
+    assert!(stderr.contains("big.txt: zet would need about"), "{stderr}");
+    // And `index build` takes no options but -o
+    run(["index build --count", block, "-o", index]).unwrap_err();
+
+    // A corrupt index is rejected, and so is one whose source has changed
+    let mut bytes = std::fs::read(index).unwrap();
+    let last = bytes.len() - 1;
+    bytes[last] ^= 1;
+    std::fs::write(index, bytes).unwrap();
+    let error = run(["diff", feed, index]).unwrap_err();
+    let stderr = String::from_utf8(error.as_output().unwrap().stderr.clone()).unwrap();
+    assert!(stderr.contains("is corrupt"), "{stderr}");
+    run(["index build", block, "-o", index]).unwrap();
+    std::fs::write(block, "b\nx\nzz\nnew\n").unwrap();
+    let error = run(["diff", feed, index]).unwrap_err();
+    let stderr = String::from_utf8(error.as_output().unwrap().stderr.clone()).unwrap();
+    assert!(stderr.contains("is stale"), "{stderr}");
+}
+
+#[test]
+fn watch_rewrites_the_output_when_an_operand_changes() {
+    let temp = TempDir::new().unwrap();
+    let x = &path_with(&temp, "x.txt", "a\nb\n", Encoding::Plain);
+    let y = &path_with(&temp, "y.txt", "b\nc\n", Encoding::Plain);
+    let out = temp.path().join("out.txt");
+    let mut child = main_binary()
+        .args(["--watch", "intersect", x, y, "-o", &out.to_string_lossy()])
+        .spawn()
+        .unwrap();
+    let wait_for = |expected: &str| {
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+        while std::fs::read_to_string(&out).ok().as_deref() != Some(expected) {
+            assert!(std::time::Instant::now() < deadline, "never saw {expected:?}");
+            std::thread::sleep(std::time::Duration::from_millis(50));
+        }
+    };
+    wait_for("b\n");
+    std::fs::write(y, "a\nc\n").unwrap();
+    wait_for("a\n");
+    child.kill().unwrap();
+    child.wait().unwrap();
+}